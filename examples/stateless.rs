@@ -1,10 +1,12 @@
 // This is not a hard dependency.
 // Just used for loading the username, password and homeserverurl from a file.
 extern crate config;
+// Just used for printing the logs of the bot to stdout
+extern crate tracing_subscriber;
 
 extern crate matrix_bot_api;
 use matrix_bot_api::handlers::{HandleResult, Message, StatelessHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, MessageType};
+use matrix_bot_api::{html, ActiveBot, MatrixBot, MessageType};
 
 // Handle that prints "I'm a bot." as a room-notice on command !whoareyou
 fn whoareyou(bot: &ActiveBot, message: &Message, _tail: &str) -> HandleResult {
@@ -46,15 +48,16 @@ fn main() {
 
     // Simply echo what was given to you by !echo XY (will print only "Echo: XY", !echo is stripped)
     handler.register_handle("echo", |bot, message, tail| {
+        // Users could make the bot notify everyone with "!echo @room"
         bot.send_message(
-            &format!("Echo: {}", tail),
+            &format!("Echo: {}", html::neutralize_mentions(tail)),
             &message.room,
             MessageType::TextMessage,
         );
         HandleResult::StopHandling
     });
 
-    // Shutdown on !shutdown. This does not leave any rooms.
+    // Shutdown on !shutdown. This does not leave any rooms (see MatrixBot::set_shutdown_mode()).
     handler.register_handle("shutdown", |bot, _room, _cmd| {
         bot.shutdown();
         HandleResult::StopHandling
@@ -63,8 +66,12 @@ fn main() {
     // Give the handler to your new bot
     let mut bot = MatrixBot::new(handler);
 
-    // Optional: To get all Matrix-message coming in and going out (quite verbose!)
-    bot.set_verbose(true);
+    // Optional: Shut down gracefully on Ctrl-C or SIGTERM, just like on !shutdown
+    bot.set_handle_signals(true);
+
+    // Optional: Print what the bot is doing. Set the env-variable RUST_LOG=matrix_bot_api=trace
+    // to get all Matrix-message coming in and going out (quite verbose!)
+    tracing_subscriber::fmt::init();
 
     // Blocking call (until shutdown). Handles all incoming messages and calls the associated functions.
     // The bot will automatically join room it is invited to.
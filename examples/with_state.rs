@@ -19,7 +19,7 @@ impl CounterHandler {
 }
 
 // Implement the trait MessageHandler, to be able to give it to our MatrixBot.
-// This trait only has one function: handle_message() and will be called on each
+// This trait has one mandatory function: handle_message() and will be called on each
 // new (text-)message in the room the bot is in.
 impl MessageHandler for CounterHandler {
     fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
@@ -47,6 +47,11 @@ impl MessageHandler for CounterHandler {
         }
         HandleResult::StopHandling
     }
+
+    // Optional: Will be called when the bot shuts down, e.g. after !shutdown
+    fn shutdown_handler(&mut self, _bot: &ActiveBot) {
+        println!("Shutting down. Final value of counter: {}", self.counter);
+    }
 }
 
 fn main() {
@@ -0,0 +1,92 @@
+use crate::{ActiveBot, Error};
+use fractal_matrix_api::backend::BKCommand;
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// How long a typing notification of a TypingGuard lasts, before it is sent again
+const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+const TYPING_REFRESH: Duration = Duration::from_secs(20);
+
+/// Shows the bot as typing in a room, until it is dropped.
+/// Created by `ActiveBot::typing_guard()`.
+///
+/// # Example
+/// ```no_run
+/// # use matrix_bot_api::{ActiveBot, MessageType};
+/// # fn run_backups() -> String { String::new() }
+/// # fn f(bot: &ActiveBot, room: &str) {
+/// let result = {
+///     let _typing = bot.typing_guard(room);
+///     run_backups()
+/// };
+/// bot.send_message(&result, room, MessageType::RoomNotice);
+/// # }
+/// ```
+pub struct TypingGuard {
+    // Dropping it stops the thread sending the notifications
+    _stop: Sender<()>,
+}
+
+/// Typing notifications and read receipts
+impl ActiveBot {
+    /// Shows (or stops showing) the bot as typing in the given room.
+    /// The notification ends after the given timeout, if it is not sent again.
+    /// This call is blocking.
+    pub fn typing(&self, room_id: &str, typing: bool, timeout: Duration) -> Result<(), Error> {
+        let path = format!(
+            "rooms/{}/typing/{}",
+            encode_uid(room_id),
+            encode_uid(&self.user_id())
+        );
+        let mut attrs = json!({ "typing": typing });
+        if typing {
+            attrs["timeout"] = json!(timeout.as_millis() as u64);
+        }
+        self.api("put", &path, &attrs).map(|_| ())
+    }
+
+    /// Shows the bot as typing in the given room, until the returned guard is dropped.
+    /// Use it for long running commands, so that they do not look dead.
+    pub fn typing_guard(&self, room_id: &str) -> TypingGuard {
+        let (stop, stopped) = channel::<()>();
+        let bot = self.clone();
+        let room_id = room_id.to_string();
+        thread::spawn(move || loop {
+            if let Err(e) = bot.typing(&room_id, true, TYPING_TIMEOUT) {
+                error!(
+                    target: "matrix_bot_api::send",
+                    "Sending typing notification failed: {:?}", e
+                );
+            }
+            match stopped.recv_timeout(TYPING_REFRESH) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => {
+                    debug!(
+                        target: "matrix_bot_api::send",
+                        room = room_id.as_str(),
+                        "Stopped typing"
+                    );
+                    let _ = bot.typing(&room_id, false, TYPING_TIMEOUT);
+                    break;
+                }
+            }
+        });
+        TypingGuard { _stop: stop }
+    }
+
+    /// Moves the read marker and the read receipt of the bot to the given event.
+    /// Use it together with `MatrixBot::set_update_read_marker(false)`, to acknowledge
+    /// only the messages the bot acted on.
+    pub fn mark_read(&self, room_id: &str, event_id: &str) {
+        self.backend
+            .send(BKCommand::MarkAsRead(
+                room_id.to_string(),
+                event_id.to_string(),
+            ))
+            .unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+//! Running the bot as an application service (e.g. for bridges).
+//!
+//! Instead of logging in and syncing, the homeserver pushes all events of interest to
+//! an HTTP-listener of the bot. Messages are given to the same handlers as with
+//! `MatrixBot::run()`, and invites for the bot or any user in its namespace are accepted.
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::appservice::AppService;
+//! use matrix_bot_api::MatrixBot;
+//! # use matrix_bot_api::handlers::StatelessHandler;
+//!
+//! let appservice = AppService::new("mybridge", "bridgebot", "your.homeserver")
+//!     .url("http://localhost:9000")
+//!     .listen("127.0.0.1:9000")
+//!     .tokens("secret_as_token", "secret_hs_token")
+//!     .user_namespace("@bridge_.*:your.homeserver", true);
+//!
+//! // Add this file to the app_service_config_files of your homeserver
+//! std::fs::write("registration.yaml", appservice.registration_yaml()).unwrap();
+//!
+//! let bot = MatrixBot::new(StatelessHandler::new());
+//! bot.run_appservice(appservice, "https://your.homeserver");
+//! ```
+use crate::ActiveBot;
+use fractal_matrix_api::backend::BKResponse;
+use fractal_matrix_api::types::Message;
+use fractal_matrix_api::util::encode_uid;
+use regex::Regex;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::VecDeque;
+use std::sync::mpsc::Sender;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, error, info, warn};
+use url::{form_urlencoded, percent_encoding::percent_decode};
+
+/// How many transaction-ids are remembered, to detect transactions sent twice
+const SEEN_TRANSACTIONS: usize = 100;
+
+/// Registration and runtime-settings of an application service
+#[derive(Clone)]
+pub struct AppService {
+    id: String,
+    url: String,
+    listen_addr: String,
+    as_token: String,
+    hs_token: String,
+    sender_localpart: String,
+    server_name: String,
+    users: Vec<(String, bool)>,
+    aliases: Vec<(String, bool)>,
+    rooms: Vec<(String, bool)>,
+}
+
+impl AppService {
+    /// * id:               Unique id of the application service
+    /// * sender_localpart: Localpart of the user of the bot itself (e.g. "bridgebot")
+    /// * server_name:      The name of the homeserver (e.g. "your.homeserver")
+    pub fn new(id: &str, sender_localpart: &str, server_name: &str) -> AppService {
+        AppService {
+            id: id.to_string(),
+            url: "http://localhost:9000".to_string(),
+            listen_addr: "127.0.0.1:9000".to_string(),
+            as_token: String::new(),
+            hs_token: String::new(),
+            sender_localpart: sender_localpart.to_string(),
+            server_name: server_name.to_string(),
+            users: vec![],
+            aliases: vec![],
+            rooms: vec![],
+        }
+    }
+
+    /// The URL the homeserver sends the events to
+    /// Default: "http://localhost:9000"
+    pub fn url(mut self, url: &str) -> AppService {
+        self.url = url.to_string();
+        self
+    }
+
+    /// The address the HTTP-listener binds to
+    /// Default: "127.0.0.1:9000"
+    pub fn listen(mut self, addr: &str) -> AppService {
+        self.listen_addr = addr.to_string();
+        self
+    }
+
+    /// The secret tokens of the registration.
+    /// * as_token: Used by the bot to authenticate against the homeserver
+    /// * hs_token: Used by the homeserver to authenticate against the bot
+    pub fn tokens(mut self, as_token: &str, hs_token: &str) -> AppService {
+        self.as_token = as_token.to_string();
+        self.hs_token = hs_token.to_string();
+        self
+    }
+
+    /// Claim all user-ids matching the given regex. Can be called multiple times.
+    pub fn user_namespace(mut self, regex: &str, exclusive: bool) -> AppService {
+        self.users.push((regex.to_string(), exclusive));
+        self
+    }
+
+    /// Claim all room-aliases matching the given regex. Can be called multiple times.
+    pub fn alias_namespace(mut self, regex: &str, exclusive: bool) -> AppService {
+        self.aliases.push((regex.to_string(), exclusive));
+        self
+    }
+
+    /// Receive events of all rooms whose id matches the given regex.
+    /// Can be called multiple times.
+    pub fn room_namespace(mut self, regex: &str, exclusive: bool) -> AppService {
+        self.rooms.push((regex.to_string(), exclusive));
+        self
+    }
+
+    /// The user-id of the bot itself
+    pub fn bot_user_id(&self) -> String {
+        format!("@{}:{}", self.sender_localpart, self.server_name)
+    }
+
+    pub(crate) fn as_token(&self) -> &str {
+        &self.as_token
+    }
+
+    /// The registration file, that has to be given to the homeserver
+    pub fn registration_yaml(&self) -> String {
+        let namespace = |entries: &Vec<(String, bool)>| -> Vec<JsonValue> {
+            entries
+                .iter()
+                .map(|(regex, exclusive)| json!({ "exclusive": exclusive, "regex": regex }))
+                .collect()
+        };
+        let registration = json!({
+            "id": self.id,
+            "url": self.url,
+            "as_token": self.as_token,
+            "hs_token": self.hs_token,
+            "sender_localpart": self.sender_localpart,
+            "rate_limited": false,
+            "namespaces": {
+                "users": namespace(&self.users),
+                "aliases": namespace(&self.aliases),
+                "rooms": namespace(&self.rooms),
+            },
+        });
+        serde_yaml::to_string(&registration).unwrap_or_default()
+    }
+
+    /// Starts a background-thread, that receives the transactions of the homeserver
+    /// and gives all messages to the bot via `tx`
+    pub(crate) fn serve(self, tx: Sender<BKResponse>, bot: ActiveBot) {
+        let server = match Server::http(&self.listen_addr) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(
+                    target: "matrix_bot_api::appservice",
+                    "Could not start listener on {}: {}", self.listen_addr, e
+                );
+                return;
+            }
+        };
+        info!(
+            target: "matrix_bot_api::appservice",
+            addr = self.listen_addr.as_str(),
+            "Listening for transactions"
+        );
+
+        let listener = Listener {
+            user_namespaces: self
+                .users
+                .iter()
+                .filter_map(|(regex, _)| match Regex::new(regex) {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        error!(target: "matrix_bot_api::appservice", "Invalid regex: {}", e);
+                        None
+                    }
+                })
+                .collect(),
+            bot_user_id: self.bot_user_id(),
+            appservice: self,
+            seen_transactions: VecDeque::new(),
+            tx,
+            bot,
+        };
+
+        thread::spawn(move || listener.run(server));
+    }
+}
+
+struct Listener {
+    appservice: AppService,
+    bot_user_id: String,
+    user_namespaces: Vec<Regex>,
+    seen_transactions: VecDeque<String>,
+    tx: Sender<BKResponse>,
+    bot: ActiveBot,
+}
+
+impl Listener {
+    fn run(mut self, server: Server) {
+        for mut request in server.incoming_requests() {
+            let (status, body) = self.handle_request(&mut request);
+            let content_type =
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+            let response = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(content_type);
+            if let Err(e) = request.respond(response) {
+                error!(target: "matrix_bot_api::appservice", "Could not answer request: {}", e);
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: &mut Request) -> (u16, JsonValue) {
+        let url = request.url().to_string();
+        let mut parts = url.splitn(2, '?');
+        let path = parts.next().unwrap_or_default();
+        let query = parts.next().unwrap_or_default();
+
+        if let Err(status) = self.authenticate(request, query) {
+            warn!(target: "matrix_bot_api::appservice", "Rejected request with invalid hs_token");
+            let errcode = if status == 401 {
+                "M_UNAUTHORIZED"
+            } else {
+                "M_FORBIDDEN"
+            };
+            return (status, json!({ "errcode": errcode }));
+        }
+
+        let path = path
+            .trim_start_matches("/_matrix/app/v1")
+            .trim_start_matches('/');
+        let mut segments = path.splitn(2, '/');
+        let kind = segments.next().unwrap_or_default();
+        let argument = percent_decode(segments.next().unwrap_or_default().as_bytes())
+            .decode_utf8_lossy()
+            .to_string();
+
+        match (request.method(), kind) {
+            (Method::Put, "transactions") => {
+                let mut body = vec![];
+                if let Err(e) = request.as_reader().read_to_end(&mut body) {
+                    warn!(target: "matrix_bot_api::appservice", "Could not read request: {}", e);
+                    return (400, json!({ "errcode": "M_NOT_JSON" }));
+                }
+                match serde_json::from_slice::<JsonValue>(&body) {
+                    Ok(transaction) => {
+                        self.handle_transaction(&argument, &transaction);
+                        (200, json!({}))
+                    }
+                    Err(_) => (400, json!({ "errcode": "M_NOT_JSON" })),
+                }
+            }
+            (Method::Get, "users") => {
+                if self.in_namespace(&argument) && self.register_user(&argument) {
+                    (200, json!({}))
+                } else {
+                    (404, json!({ "errcode": "M_NOT_FOUND" }))
+                }
+            }
+            _ => (404, json!({ "errcode": "M_NOT_FOUND" })),
+        }
+    }
+
+    /// The homeserver sends the hs_token as query-parameter (older versions)
+    /// or as Authorization-header
+    fn authenticate(&self, request: &Request, query: &str) -> Result<(), u16> {
+        let from_query = form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "access_token")
+            .map(|(_, value)| value.to_string());
+        let from_header = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str().trim_start_matches("Bearer ").to_string());
+
+        match from_query.or(from_header) {
+            None => Err(401),
+            Some(ref token) if *token == self.appservice.hs_token => Ok(()),
+            Some(_) => Err(403),
+        }
+    }
+
+    fn handle_transaction(&mut self, txn_id: &str, transaction: &JsonValue) {
+        if self.seen_transactions.iter().any(|t| t == txn_id) {
+            debug!(target: "matrix_bot_api::appservice", txn_id = txn_id, "Transaction sent twice");
+            return;
+        }
+        self.seen_transactions.push_back(txn_id.to_string());
+        if self.seen_transactions.len() > SEEN_TRANSACTIONS {
+            self.seen_transactions.pop_front();
+        }
+
+        let events = match transaction["events"].as_array() {
+            Some(x) => x,
+            None => return,
+        };
+
+        let mut messages = vec![];
+        for event in events {
+            let room = event["room_id"].as_str().unwrap_or_default();
+            let sender = event["sender"].as_str().unwrap_or_default();
+
+            if event["type"] == "m.room.member" && event["content"]["membership"] == "invite" {
+                let invitee = event["state_key"].as_str().unwrap_or_default();
+                if invitee == self.bot_user_id || self.in_namespace(invitee) {
+                    self.join(room, invitee);
+                }
+            } else if Message::supported_event(&event) {
+                // Do not give messages of our own puppets back to the handlers
+                if sender != self.bot_user_id && self.in_namespace(sender) {
+                    continue;
+                }
+                messages.push(Message::parse_room_message(room, event));
+            }
+        }
+
+        if !messages.is_empty() {
+            self.tx.send(BKResponse::RoomMessages(messages)).unwrap();
+        }
+    }
+
+    fn in_namespace(&self, user_id: &str) -> bool {
+        self.user_namespaces.iter().any(|r| r.is_match(user_id))
+    }
+
+    fn join(&self, room: &str, user_id: &str) {
+        info!(
+            target: "matrix_bot_api::appservice",
+            room = room,
+            user = user_id,
+            "Joining room"
+        );
+        let path = format!("join/{}", encode_uid(room));
+        let puppet = self.bot.as_user(user_id);
+        if let Err(e) = puppet.api("post", &path, &JsonValue::Null) {
+            error!(target: "matrix_bot_api::appservice", "Joining room failed: {:?}", e);
+        }
+    }
+
+    /// Creates the given user on the homeserver. Returns false, if that failed.
+    fn register_user(&self, user_id: &str) -> bool {
+        let localpart = user_id
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or_default();
+        let attrs = json!({
+            "type": "m.login.application_service",
+            "username": localpart,
+        });
+        match self.bot.api("post", "register", &attrs) {
+            Ok(_) => {
+                info!(target: "matrix_bot_api::appservice", user = user_id, "Registered user");
+                true
+            }
+            Err(fractal_matrix_api::error::Error::MatrixError(ref js))
+                if js["errcode"] == "M_USER_IN_USE" =>
+            {
+                true
+            }
+            Err(e) => {
+                error!(target: "matrix_bot_api::appservice", "Registering user failed: {:?}", e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Runs a bot with built-in plugins, without writing any Rust.
+//!
+//! Usage: `matrix-bot [config-file]` (default: "botconfig.toml")
+//!
+//! The configuration file is loaded by `BotConfig` (see its documentation for all settings)
+//! and is reloaded whenever it changes. The plugins to enable are listed in it:
+//! ```toml
+//! plugins = ["help", "ping", "echo", "dice", "uptime", "webhook"]
+//! ```
+//! * help:    `!help` lists the commands of all enabled plugins
+//! * ping:    `!ping` answers with "pong"
+//! * echo:    `!echo <text>` repeats the text
+//! * dice:    `!roll X [X ..]` rolls up to 20 dice with X (up to 1000) eyes each
+//! * uptime:  `!uptime` tells how long the bot is running
+//! * webhook: Relays the configured `webhooks` into rooms (needs `webhook_listen`)
+//!
+//! Further handlers are loaded from the `plugin_libraries` (see `matrix_bot_api::plugin`):
+//! ```toml
+//! [[plugin_libraries]]
+//! path = "/usr/lib/matrix-bot/libgreeter.so"
+//! config = { greeting = "Moin" }
+//! ```
+//!
+//! The bot shuts down gracefully on SIGINT and SIGTERM. Set the env-variable
+//! `RUST_LOG=matrix_bot_api=debug` to see what it is doing.
+use matrix_bot_api::handlers::{split_command, HandleResult, Message, MessageHandler};
+use matrix_bot_api::{html, ActiveBot, BotConfig, MessageType};
+use rand::Rng;
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const DEFAULT_CONFIG: &str = "botconfig.toml";
+const DEFAULT_PREFIX: &str = "!";
+/// Limits of the dice plugin, to keep the answers short
+const MAX_DICE: usize = 20;
+const MAX_EYES: u64 = 1000;
+
+/// The built-in plugins, that answer commands
+#[derive(Clone, Copy)]
+enum Plugin {
+    Help,
+    Ping,
+    Echo,
+    Dice,
+    Uptime,
+}
+
+impl Plugin {
+    fn from_name(name: &str) -> Option<Plugin> {
+        match name {
+            "help" => Some(Plugin::Help),
+            "ping" => Some(Plugin::Ping),
+            "echo" => Some(Plugin::Echo),
+            "dice" => Some(Plugin::Dice),
+            "uptime" => Some(Plugin::Uptime),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Plugin::Help => "help",
+            Plugin::Ping => "ping",
+            Plugin::Echo => "echo",
+            Plugin::Dice => "dice",
+            Plugin::Uptime => "uptime",
+        }
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            Plugin::Help => "help: Shows this help",
+            Plugin::Ping => "ping: Checks whether the bot is alive",
+            Plugin::Echo => "echo <text>: Repeats the text",
+            Plugin::Dice => "roll X [X ..]: Rolls dice with X eyes each",
+            Plugin::Uptime => "uptime: Shows how long the bot is running",
+        }
+    }
+
+    fn command(self) -> &'static str {
+        match self {
+            Plugin::Dice => "roll",
+            _ => self.name(),
+        }
+    }
+}
+
+/// Handler of one built-in plugin
+struct PluginHandler {
+    plugin: Plugin,
+    /// The usage of all enabled plugins, for "help"
+    usage: Vec<&'static str>,
+    started: Instant,
+}
+
+impl MessageHandler for PluginHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = bot
+            .room_prefix(&message.room)
+            .unwrap_or_else(|| DEFAULT_PREFIX.to_string());
+        let tail = match split_command(&message.body, &prefix) {
+            Some((command, tail)) if command == self.plugin.command() => tail,
+            _ => return HandleResult::ContinueHandling,
+        };
+
+        let reply = match self.plugin {
+            Plugin::Help => {
+                let lines: Vec<String> = self
+                    .usage
+                    .iter()
+                    .map(|usage| format!("{}{}", prefix, usage))
+                    .collect();
+                lines.join("\n")
+            }
+            Plugin::Ping => "pong".to_string(),
+            // Users could make the bot notify everyone with "!echo @room"
+            Plugin::Echo => html::neutralize_mentions(tail),
+            Plugin::Dice => roll_dice(&mut rand::thread_rng(), tail),
+            Plugin::Uptime => format_duration(self.started.elapsed()),
+        };
+        bot.send_message(&reply, &message.room, MessageType::RoomNotice);
+        HandleResult::StopHandling
+    }
+
+    fn name(&self) -> &str {
+        self.plugin.name()
+    }
+}
+
+fn roll_dice<R: Rng>(rng: &mut R, tail: &str) -> String {
+    let dice: Vec<&str> = tail.split_whitespace().collect();
+    if dice.len() > MAX_DICE {
+        return format!("Roll at most {} dice at once", MAX_DICE);
+    }
+    let mut results: Vec<u64> = vec![];
+    for eyes in dice {
+        match eyes.parse::<u64>() {
+            Ok(eyes) if eyes > 0 && eyes <= MAX_EYES => results.push(rng.gen_range(1, eyes + 1)),
+            Ok(_) => return format!("A die has 1 to {} eyes", MAX_EYES),
+            Err(_) => return format!("\"{}\" is not a number of eyes", eyes),
+        }
+    }
+    match results.len() {
+        0 => "Give the number of eyes of each die, e.g. \"roll 6 12\"".to_string(),
+        1 => results[0].to_string(),
+        _ => {
+            // Can not overflow with the limits above
+            let sum: u64 = results.iter().sum();
+            let results: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+            format!("{} (sum: {})", results.join(", "), sum)
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "Running for {}d {}h {}m {}s",
+        secs / 86400,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_CONFIG.to_string());
+    let config = match BotConfig::from_file(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut plugins = vec![];
+    let mut webhook = false;
+    for name in &config.plugins {
+        match Plugin::from_name(name) {
+            Some(plugin) => plugins.push(plugin),
+            None if name == "webhook" => webhook = true,
+            None => {
+                eprintln!("Unknown plugin \"{}\"", name);
+                process::exit(1);
+            }
+        }
+    }
+    if webhook && config.webhook_listen.is_none() {
+        eprintln!("The webhook plugin needs webhook_listen in {}", path);
+        process::exit(1);
+    }
+    if webhook && config.webhooks.is_empty() {
+        warn!(
+            "The webhook plugin is enabled, but {} has no webhooks",
+            path
+        );
+    }
+    // The bot needs at least one handler
+    if plugins.is_empty() {
+        plugins.push(Plugin::Help);
+    }
+
+    let usage: Vec<&'static str> = plugins.iter().map(|p| p.usage()).collect();
+    let started = Instant::now();
+    let mut handlers = plugins.into_iter().map(|plugin| PluginHandler {
+        plugin,
+        usage: usage.clone(),
+        started,
+    });
+
+    let mut bot = config.build(handlers.next().unwrap());
+    for handler in handlers {
+        bot.add_handler(handler);
+    }
+    if webhook {
+        config.add_webhooks(&mut bot);
+    }
+    if let Err(e) = config.load_plugins(&mut bot) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    bot.watch_config_file(&path);
+    bot.set_handle_signals(true);
+
+    // Blocking call (until shutdown)
+    if let Err(e) = config.run(bot) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dice() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let result: u64 = roll_dice(&mut rng, "6").parse().unwrap();
+            assert!((1..=6).contains(&result));
+        }
+        assert_eq!(roll_dice(&mut rng, "1 1"), "1, 1 (sum: 2)");
+        assert!(roll_dice(&mut rng, "1000").parse::<u64>().is_ok());
+    }
+
+    #[test]
+    fn invalid_dice() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(
+            roll_dice(&mut rng, "six"),
+            "\"six\" is not a number of eyes"
+        );
+        assert_eq!(roll_dice(&mut rng, "0"), "A die has 1 to 1000 eyes");
+        assert_eq!(roll_dice(&mut rng, "1001"), "A die has 1 to 1000 eyes");
+        assert_eq!(
+            roll_dice(&mut rng, "18446744073709551615"),
+            "A die has 1 to 1000 eyes"
+        );
+        assert_eq!(
+            roll_dice(&mut rng, &"6 ".repeat(21)),
+            "Roll at most 20 dice at once"
+        );
+        assert!(roll_dice(&mut rng, "").starts_with("Give the number"));
+    }
+}
@@ -0,0 +1,526 @@
+use crate::handlers::MessageHandler;
+use crate::persistent_rooms::PersistentRoom;
+#[cfg(feature = "plugins")]
+use crate::plugin::{DynamicHandler, PluginError};
+use crate::settings::Settings;
+#[cfg(feature = "webhook")]
+use crate::webhook::{WebhookAuth, WebhookRoute};
+use crate::{ActiveBot, Error, InvitePolicy, LoopEvent, MatrixBot, RateLimit};
+use ::config::{Config, Environment, File};
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{debug_span, error, info};
+
+pub use ::config::ConfigError;
+
+/// The prefix of environment variables, that override the configuration
+/// (e.g. `MATRIX_BOT_PASSWORD`)
+const ENV_PREFIX: &str = "MATRIX_BOT";
+
+/// How often the watched configuration file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything needed to run a bot, loaded from a configuration file.
+///
+/// # Example
+/// A `botconfig.toml`:
+/// ```toml
+/// homeserver_url = "https://matrix.example.org"
+/// user = "bot"
+/// password = "secret"
+/// room_commands = "!bot "
+/// admins = ["@alice:example.org"]
+/// invite_servers = ["example.org"]
+/// rate_limit = { messages = 5, seconds = 10 }
+/// data_dir = "/var/lib/bot"
+///
+/// [[rooms]]
+/// room = "#general:example.org"
+/// join = true
+///
+/// [[rooms]]
+/// room = "!ABCdef:example.org"
+/// prefix = "?"
+/// disabled = ["dice"]
+/// settings = { greeting = "Moin!" }
+/// ```
+///
+/// ```no_run
+/// use matrix_bot_api::handlers::StatelessHandler;
+/// use matrix_bot_api::BotConfig;
+///
+/// let config = BotConfig::from_file("botconfig.toml").unwrap();
+/// let mut bot = config.build(StatelessHandler::new());
+/// bot.watch_config_file("botconfig.toml");
+/// config.run(bot).unwrap();
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct BotConfig {
+    pub homeserver_url: String,
+    /// The user name or user-id of the bot
+    pub user: String,
+    /// Log in with this password ...
+    #[serde(default)]
+    pub password: Option<String>,
+    /// ... or use this access token (needs the full user-id as `user`)
+    #[serde(default)]
+    pub access_token: Option<String>,
+    /// The command prefix of all rooms without their own prefix
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Enables the room commands with this prefix (see `MatrixBot::set_room_commands()`)
+    #[serde(default)]
+    pub room_commands: Option<String>,
+    /// The user-ids of the admins of the bot
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Whether the bot accepts invites at all
+    #[serde(default = "default_true")]
+    pub accept_invites: bool,
+    /// Only accept invites of users of these homeservers (empty for all)
+    #[serde(default)]
+    pub invite_servers: Vec<String>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// The directory handlers can store their data in
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub rooms: Vec<RoomEntry>,
+    /// The address of the webhook-listener (see `BotConfig::add_webhooks()`)
+    #[serde(default)]
+    pub webhook_listen: Option<String>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookEntry>,
+    /// Handlers to load from shared libraries (see `BotConfig::load_plugins()`)
+    #[serde(default)]
+    pub plugin_libraries: Vec<PluginLibrary>,
+    /// Names of the built-in plugins the `matrix-bot` runner enables
+    /// (e.g. "echo", "dice", "ping", "uptime", "help" and "webhook")
+    #[serde(default)]
+    pub plugins: Vec<String>,
+}
+
+/// How many messages each user may send in how many seconds
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    pub messages: usize,
+    pub seconds: u64,
+}
+
+/// Why `BotConfig::run()` failed
+#[derive(Debug)]
+pub enum RunError {
+    /// The configuration has neither a password nor an access token
+    MissingCredentials,
+    /// Logging in with the password failed
+    Login(Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::MissingCredentials => {
+                write!(f, "The configuration needs a password or an access token")
+            }
+            RunError::Login(e) => write!(f, "Login failed: {:?}", e),
+        }
+    }
+}
+
+/// A room the bot should join or that has its own default configuration
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomEntry {
+    /// The room-id, or an alias for rooms without configuration
+    pub room: String,
+    /// Whether the bot should always be in this room (see `MatrixBot::add_persistent_room()`)
+    #[serde(default)]
+    pub join: bool,
+    /// Servers to join through
+    #[serde(default)]
+    pub via: Vec<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Names of the handlers that are disabled in this room
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    /// Settings of the handlers in this room (see `ActiveBot::room_setting()`).
+    /// The keys are converted to lower case.
+    #[serde(default)]
+    pub settings: HashMap<String, JsonValue>,
+}
+
+/// A shared library with a handler (see the `plugin` module)
+#[derive(Clone, Debug, Deserialize)]
+pub struct PluginLibrary {
+    pub path: PathBuf,
+    /// Given to the plugin when it is created
+    #[serde(default)]
+    pub config: JsonValue,
+}
+
+/// A webhook route, that relays payloads into a room (see `webhook::WebhookRoute`)
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookEntry {
+    pub path: String,
+    pub room: String,
+    /// The template of the plain text message
+    pub plain: String,
+    /// The template of the HTML message (the plain template, if not given)
+    #[serde(default)]
+    pub html: Option<String>,
+    /// Send room notices instead of text messages
+    #[serde(default)]
+    pub notice: bool,
+    /// The header containing the secret or the signature of requests
+    #[serde(default)]
+    pub header: Option<String>,
+    /// The shared secret, that requests have to contain in the header
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Whether the header contains the HMAC-SHA256 of the request (made with the secret),
+    /// instead of the secret itself
+    #[serde(default)]
+    pub hmac: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl BotConfig {
+    /// Loads the configuration from the given file. The format is taken from the extension
+    /// (e.g. ".toml", ".yaml" or ".json"). Environment variables like `MATRIX_BOT_PASSWORD`
+    /// override the values of the file.
+    pub fn from_file(path: &str) -> Result<BotConfig, ConfigError> {
+        let mut config = Config::default();
+        config
+            .merge(File::with_name(path))?
+            .merge(Environment::with_prefix(ENV_PREFIX))?;
+        config.try_into::<BotConfig>()?.checked()
+    }
+
+    /// Loads the configuration from environment variables only
+    /// (`MATRIX_BOT_HOMESERVER_URL`, `MATRIX_BOT_USER`, `MATRIX_BOT_PASSWORD`, ...)
+    pub fn from_env() -> Result<BotConfig, ConfigError> {
+        let mut config = Config::default();
+        config.merge(Environment::with_prefix(ENV_PREFIX))?;
+        config.try_into::<BotConfig>()?.checked()
+    }
+
+    /// Makes sure the bot can log in with the credentials of the configuration
+    fn checked(self) -> Result<BotConfig, ConfigError> {
+        match (&self.access_token, &self.password) {
+            (None, None) => Err(ConfigError::Message(
+                "The configuration needs a password or an access token".to_string(),
+            )),
+            (Some(_), _) if !self.user.starts_with('@') => Err(ConfigError::Message(
+                "An access token needs the full user-id as user".to_string(),
+            )),
+            _ => Ok(self),
+        }
+    }
+
+    /// Creates a bot with the given handler and applies this configuration to it
+    pub fn build<M>(&self, handler: M) -> MatrixBot
+    where
+        M: MessageHandler + 'static + Send,
+    {
+        let mut bot = MatrixBot::new(handler);
+        bot.set_file_config(self.clone());
+        bot
+    }
+
+    /// Applies everything but the credentials to the given bot, as if it was set in code.
+    /// Unlike with `build()`, the values are not replaced by `MatrixBot::watch_config_file()`.
+    pub fn apply(&self, bot: &mut MatrixBot) {
+        if self.room_commands.is_some() {
+            bot.set_room_commands(self.room_commands.as_deref());
+        }
+        for entry in self.rooms.iter().filter(|entry| entry.join) {
+            let via: Vec<&str> = entry.via.iter().map(|s| s.as_str()).collect();
+            bot.add_persistent_room(&entry.room, &via);
+        }
+        self.apply_settings(&mut bot.code_settings);
+        bot.update_settings();
+    }
+
+    /// Applies the values this configuration contains over the given settings
+    pub(crate) fn apply_settings(&self, settings: &mut Settings) {
+        if self.prefix.is_some() {
+            settings.default_prefix = self.prefix.clone();
+        }
+        settings.admins.extend(self.admins.iter().cloned());
+        if let Some(policy) = self.invite_policy() {
+            settings.invite_policy = policy;
+        }
+        if let Some(limit) = &self.rate_limit {
+            settings.rate_limit = Some(RateLimit {
+                messages: limit.messages,
+                per: Duration::from_secs(limit.seconds),
+            });
+        }
+        if self.data_dir.is_some() {
+            settings.data_dir = self.data_dir.clone();
+        }
+
+        for entry in &self.rooms {
+            let configured =
+                entry.prefix.is_some() || !entry.disabled.is_empty() || !entry.settings.is_empty();
+            if configured {
+                let defaults = json!({
+                    "prefix": entry.prefix,
+                    "disabled": entry.disabled,
+                    "settings": entry.settings,
+                });
+                settings.room_defaults.insert(entry.room.clone(), defaults);
+            }
+        }
+    }
+
+    /// Starts the webhook-listener with the configured routes, when the bot is run.
+    /// Unlike the other settings, webhooks are not reloaded.
+    #[cfg(feature = "webhook")]
+    pub fn add_webhooks(&self, bot: &mut MatrixBot) {
+        if let Some(addr) = &self.webhook_listen {
+            bot.set_webhook_listener(addr);
+        }
+        for entry in &self.webhooks {
+            let html = entry.html.as_ref().unwrap_or(&entry.plain);
+            let mut route =
+                WebhookRoute::with_template(&entry.path, &entry.room, &entry.plain, html)
+                    .as_notice(entry.notice);
+            if let Some(secret) = &entry.secret {
+                let secret = secret.clone();
+                route = route.auth(if entry.hmac {
+                    let header = entry.header.as_deref().unwrap_or("X-Hub-Signature-256");
+                    WebhookAuth::HmacSha256 {
+                        header: header.to_string(),
+                        secret,
+                    }
+                } else {
+                    let header = entry.header.as_deref().unwrap_or("X-Webhook-Token");
+                    WebhookAuth::SharedSecret {
+                        header: header.to_string(),
+                        secret,
+                    }
+                });
+            }
+            bot.add_webhook_route(route);
+        }
+    }
+
+    /// Loads the handlers of all plugin libraries and adds them to the given bot.
+    /// Like webhooks, plugins are not reloaded.
+    #[cfg(feature = "plugins")]
+    pub fn load_plugins(&self, bot: &mut MatrixBot) -> Result<(), PluginError> {
+        for library in &self.plugin_libraries {
+            bot.add_handler(DynamicHandler::load(&library.path, &library.config)?);
+        }
+        Ok(())
+    }
+
+    /// Runs the given bot (see `MatrixBot::run()`), logging in with the password or
+    /// the access token of this configuration. Returns an error, if the login failed.
+    pub fn run(&self, bot: MatrixBot) -> Result<(), RunError> {
+        match (&self.access_token, &self.password) {
+            (Some(token), _) => {
+                bot.run_with_token(&self.user, token, &self.homeserver_url);
+                Ok(())
+            }
+            (None, Some(password)) => bot
+                .try_run(&self.user, password, &self.homeserver_url)
+                .map_err(RunError::Login),
+            (None, None) => Err(RunError::MissingCredentials),
+        }
+    }
+
+    /// The invite policy, unless the configuration accepts all invites (the default)
+    fn invite_policy(&self) -> Option<InvitePolicy> {
+        if !self.accept_invites {
+            Some(InvitePolicy::Nobody)
+        } else if self.invite_servers.is_empty() {
+            None
+        } else {
+            Some(InvitePolicy::Servers(self.invite_servers.clone()))
+        }
+    }
+}
+
+/// The configuration file a bot reloads, when it changes
+pub(crate) struct ConfigFile {
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigFile {
+    pub(crate) fn new(path: &str) -> ConfigFile {
+        ConfigFile {
+            path: path.to_string(),
+            modified: modified(path),
+        }
+    }
+
+    /// Starts a thread, that wakes the bot up when the file changed
+    pub(crate) fn watch(&self, tx: Sender<LoopEvent>) {
+        let path = self.path.clone();
+        let mut last = self.modified;
+        thread::spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            let modified = modified(&path);
+            if modified.is_some() && modified != last {
+                last = modified;
+                // The bot is gone
+                if tx.send(LoopEvent::ConfigFileChanged).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl MatrixBot {
+    /// Whether the watched configuration file changed since it was loaded
+    pub(crate) fn config_file_changed(&self) -> bool {
+        match &self.config_file {
+            Some(file) => {
+                let modified = modified(&file.path);
+                modified.is_some() && modified != file.modified
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the values of the configuration file, that are applied over the settings
+    /// made in code. Persistent rooms the file no longer contains are not rejoined anymore.
+    pub(crate) fn set_file_config(&mut self, config: BotConfig) {
+        let joined: Vec<&RoomEntry> = config.rooms.iter().filter(|entry| entry.join).collect();
+        self.persistent_rooms.retain(|persistent| {
+            let keep = !persistent.from_file || joined.iter().any(|e| e.room == persistent.room);
+            if !keep {
+                info!(
+                    target: "matrix_bot_api::rooms",
+                    room = persistent.room.as_str(),
+                    "Room is no longer persistent"
+                );
+            }
+            keep
+        });
+        for entry in joined {
+            match self
+                .persistent_rooms
+                .iter_mut()
+                .find(|p| p.room == entry.room)
+            {
+                Some(persistent) if persistent.from_file => {
+                    persistent.via_servers = entry.via.clone()
+                }
+                Some(_) => (),
+                None => {
+                    let mut persistent = PersistentRoom::new(&entry.room, entry.via.clone());
+                    persistent.from_file = true;
+                    self.persistent_rooms.push(persistent);
+                }
+            }
+        }
+        self.file_config = Some(config);
+        self.update_settings();
+    }
+
+    /// Reloads the watched configuration file, forgets the cached room configurations and
+    /// notifies all handlers. Returns false, if loading the file failed.
+    pub(crate) fn reload_config(&mut self, active_bot: &ActiveBot) -> bool {
+        info!(target: "matrix_bot_api::sync", "Reloading configuration");
+        if let Err(e) = self.load_config_file() {
+            error!(target: "matrix_bot_api::sync", "Could not reload configuration: {}", e);
+            return false;
+        }
+        self.room_config_cache.lock().unwrap().clear();
+        self.join_persistent_rooms(active_bot);
+
+        for handler in self.handlers.iter_mut() {
+            let span = debug_span!(
+                target: "matrix_bot_api::handler",
+                "handler",
+                name = handler.name()
+            );
+            let _enter = span.enter();
+            handler.config_changed(active_bot);
+        }
+        true
+    }
+
+    /// Loads the watched configuration file (see `set_file_config()`)
+    fn load_config_file(&mut self) -> Result<(), ConfigError> {
+        let path = match &mut self.config_file {
+            Some(file) => {
+                // Do not try again on every sync, if the file is broken
+                file.modified = modified(&file.path);
+                file.path.clone()
+            }
+            None => return Ok(()),
+        };
+        let config = BotConfig::from_file(&path)?;
+        self.set_file_config(config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_values_are_applied_over_code() {
+        let config: BotConfig = serde_json::from_value(json!({
+            "homeserver_url": "https://example.org",
+            "user": "bot",
+            "password": "secret",
+            "admins": ["@alice:example.org"],
+            "rooms": [{ "room": "!a:example.org", "prefix": "?" }],
+        }))
+        .unwrap();
+
+        let mut settings = Settings::default();
+        settings.admins.insert("@bob:example.org".to_string());
+        settings.default_prefix = Some("!".to_string());
+        settings.invite_policy = InvitePolicy::Nobody;
+        config.apply_settings(&mut settings);
+
+        assert!(settings.admins.contains("@alice:example.org"));
+        assert!(settings.admins.contains("@bob:example.org"));
+        // Not set in the file
+        assert_eq!(settings.default_prefix.as_deref(), Some("!"));
+        assert!(!settings.invite_policy.accepts("@alice:example.org"));
+        assert_eq!(settings.room_defaults("!a:example.org")["prefix"], "?");
+    }
+
+    #[test]
+    fn credentials_are_checked() {
+        let config = |js: JsonValue| serde_json::from_value::<BotConfig>(js).unwrap().checked();
+        let base = json!({ "homeserver_url": "https://example.org", "user": "bot" });
+        assert!(config(base.clone()).is_err());
+
+        let mut with_token = base.clone();
+        with_token["access_token"] = json!("token");
+        assert!(config(with_token.clone()).is_err());
+        with_token["user"] = json!("@bot:example.org");
+        assert!(config(with_token).is_ok());
+
+        let mut with_password = base;
+        with_password["password"] = json!("secret");
+        assert!(config(with_password).is_ok());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{ActiveBot, Error, MessageType};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use tracing::{debug, info};
+
+/// Direct chats with users. All these calls are blocking.
+impl ActiveBot {
+    /// Sends a message to the given user in a direct chat (see `ActiveBot::direct_room()`).
+    /// Returns the room-id of the direct chat, to send more messages to it.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_bot_api::{ActiveBot, MessageType};
+    /// # use matrix_bot_api::handlers::Message;
+    /// # fn f(bot: &ActiveBot, message: &Message) {
+    /// // Reply privately, instead of spamming the room
+    /// if !bot.is_direct(&message.room) {
+    ///     let help = "Usage: !weather <city>";
+    ///     bot.dm(&message.sender, help, MessageType::RoomNotice).unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub fn dm(&self, user_id: &str, msg: &str, msgtype: MessageType) -> Result<String, Error> {
+        let room_id = self.direct_room(user_id)?;
+        self.send_message(msg, &room_id, msgtype);
+        Ok(room_id)
+    }
+
+    /// Returns the room-id of the direct chat with the given user.
+    /// An existing direct chat (from the m.direct account data of the bot) is used,
+    /// if the bot and the user are still in it (or the user is invited).
+    /// Otherwise a new room is created, the user invited and the room added to m.direct.
+    pub fn direct_room(&self, user_id: &str) -> Result<String, Error> {
+        let mut direct = self.direct_rooms()?;
+        let joined = self.joined_rooms()?;
+        let rooms = direct.entry(user_id.to_string()).or_default();
+        for room_id in rooms.iter().filter(|room| joined.contains(room)) {
+            match self.membership(room_id, user_id)?.as_deref() {
+                Some("join") | Some("invite") => return Ok(room_id.clone()),
+                _ => (),
+            }
+        }
+
+        debug!(
+            target: "matrix_bot_api::rooms",
+            user = user_id,
+            "No direct chat found, creating one"
+        );
+        let room_id = self.create_direct_room(user_id)?;
+        rooms.push(room_id.clone());
+        let path = format!("user/{}/account_data/m.direct", encode_uid(&self.user_id()));
+        self.api("put", &path, &json!(direct))?;
+        info!(
+            target: "matrix_bot_api::rooms",
+            room = room_id.as_str(),
+            user = user_id,
+            "Created direct chat"
+        );
+        Ok(room_id)
+    }
+
+    /// The membership ("join", "invite", "leave", ...) of the given user in the given room,
+    /// or None, if the user was never in the room
+    fn membership(&self, room_id: &str, user_id: &str) -> Result<Option<String>, Error> {
+        let path = format!(
+            "rooms/{}/state/m.room.member/{}",
+            encode_uid(room_id),
+            encode_uid(user_id)
+        );
+        match self.api("get", &path, &JsonValue::Null) {
+            Ok(js) => Ok(js["membership"].as_str().map(String::from)),
+            Err(Error::MatrixError(ref js)) if js["errcode"] == "M_NOT_FOUND" => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -14,13 +14,40 @@ pub enum HandleResult {
 /// be called with this message or not.
 ///
 /// The bot will also call `init_handler()` on startup to allow handlers to
-/// setup any background work
+/// setup any background work, and `shutdown_handler()` on shutdown to allow
+/// handlers to clean up after themselves.
 pub trait MessageHandler {
     /// Will be called for every text message send to a room the bot is in
     fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult;
 
+    /// Whether this handler waits for the given message, e.g. for the answer in a dialog.
+    /// Such a message is given to this handler only, no matter in which order the handlers
+    /// were registered.
+    fn expects_message(&self, _message: &Message) -> bool {
+        false
+    }
+
     /// Will be called once the bot has started
     fn init_handler(&mut self, _bot: &ActiveBot) {}
+
+    /// Will be called for every vote in a poll in the rooms of the bot, also in polls started
+    /// by other users (see `ActiveBot::start_poll()`)
+    fn handle_poll_response(&mut self, _bot: &ActiveBot, _response: &PollResponse) {}
+
+    /// Will be called after the configuration of the bot was reloaded
+    /// (see `MatrixBot::watch_config_file()`), e.g. to read changed room settings.
+    /// The handler keeps its state.
+    fn config_changed(&mut self, _bot: &ActiveBot) {}
+
+    /// Will be called once the bot is shutting down.
+    /// Messages sent from here will still be delivered before the bot stops.
+    fn shutdown_handler(&mut self, _bot: &ActiveBot) {}
+
+    /// Name of the handler, used in logs.
+    /// Default: The type-name of the handler
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
 }
 
 /// Convenience-function to split the incoming message by whitespace and
@@ -38,7 +65,56 @@ pub fn extract_command<'a>(message: &'a str, prefix: &str) -> Option<&'a str> {
     None
 }
 
+/// Like `extract_command()`, but also returns the argument of the command: The rest of
+/// the message, without surrounding whitespace.
+/// Returns None, if the message does not start with the given prefix
+/// # Example:
+/// split_command("!roll 2d6", "!") will return Some(("roll", "2d6"))
+/// split_command("!ping", "!") will return Some(("ping", ""))
+pub fn split_command<'a>(message: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    if !message.starts_with(prefix) {
+        return None;
+    }
+    let mut parts = message[prefix.len()..]
+        .trim_start()
+        .splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("");
+    let argument = parts.next().unwrap_or("").trim();
+    Some((command, argument))
+}
+
 pub mod stateless_handler;
 pub use self::stateless_handler::StatelessHandler;
+pub mod conversation_handler;
+pub use self::conversation_handler::{ConversationHandler, DialogResult, DialogStep};
+
+use crate::{ActiveBot, PollResponse};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(split_command("!enable echo", "!"), Some(("enable", "echo")));
+        assert_eq!(split_command("!prefix  ?? ", "!"), Some(("prefix", "??")));
+        assert_eq!(split_command("!features", "!"), Some(("features", "")));
+        assert_eq!(split_command("Hi all!", "!"), None);
+    }
+
+    #[test]
+    fn whitespace_after_prefix() {
+        assert_eq!(
+            split_command("! enable echo", "!"),
+            Some(("enable", "echo"))
+        );
+        assert_eq!(extract_command("! enable echo", "!"), Some("enable"));
+    }
 
-use crate::ActiveBot;
+    #[test]
+    fn multibyte_whitespace() {
+        let message = "!\u{3000}\u{a0}\u{3000}enable\u{3000}echo\u{a0}";
+        assert_eq!(split_command(message, "!"), Some(("enable", "echo")));
+        assert_eq!(split_command("→roll 6", "→"), Some(("roll", "6")));
+    }
+}
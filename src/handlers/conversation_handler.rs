@@ -0,0 +1,336 @@
+use crate::handlers::{extract_command, HandleResult, Message, MessageHandler};
+use crate::{ActiveBot, MessageType};
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// One step of a dialog. Gets the message of the user and the data the
+/// previous steps collected, and decides what happens with the next message.
+pub type DialogStep = fn(bot: &ActiveBot, message: &Message, data: &mut JsonValue) -> DialogResult;
+
+/// What to do after a step of a dialog
+pub enum DialogResult {
+    /// Give the next message of the user to the given step
+    Next(DialogStep),
+    /// The dialog is finished
+    Done,
+}
+
+struct Dialog {
+    next: DialogStep,
+    data: JsonValue,
+    last_activity: Instant,
+}
+
+/// Handler for commands that need to ask the user follow-up questions.
+///
+/// A command starts a dialog with the user who sent it, in the room it was sent in.
+/// All following messages of this user in this room are given to the current step of the
+/// dialog (and to no other handler), until the dialog is done, cancelled or timed out.
+///
+/// # Example
+/// ```no_run
+/// use matrix_bot_api::handlers::{ConversationHandler, DialogResult, Message};
+/// use matrix_bot_api::{ActiveBot, MatrixBot, MessageType};
+/// use serde_json::value::Value as JsonValue;
+///
+/// fn start(bot: &ActiveBot, message: &Message, _: &mut JsonValue) -> DialogResult {
+///     bot.send_message("What is the question?", &message.room, MessageType::RoomNotice);
+///     DialogResult::Next(question)
+/// }
+///
+/// fn question(bot: &ActiveBot, message: &Message, data: &mut JsonValue) -> DialogResult {
+///     data["question"] = JsonValue::from(message.body.clone());
+///     bot.send_message("Thanks!", &message.room, MessageType::RoomNotice);
+///     DialogResult::Done
+/// }
+///
+/// let mut conversations = ConversationHandler::new();
+/// conversations.register_dialog("ask", start);
+/// let bot = MatrixBot::new(conversations);
+/// ```
+pub struct ConversationHandler {
+    name: String,
+    cmd_prefix: String,
+    cancel_keyword: String,
+    timeout: Duration,
+    dialogs: HashMap<String, DialogStep>,
+    /// Active dialogs by room-id and user-id
+    active: HashMap<(String, String), Dialog>,
+}
+
+impl ConversationHandler {
+    pub fn new() -> ConversationHandler {
+        ConversationHandler {
+            name: "ConversationHandler".to_string(),
+            cmd_prefix: "!".to_string(),
+            cancel_keyword: "cancel".to_string(),
+            timeout: Duration::from_secs(300),
+            dialogs: HashMap::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// The name of this handler, used in logs and to enable or disable it per room.
+    /// Default: "ConversationHandler"
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// With what prefix commands to the bot will start,
+    /// unless the room has its own prefix (see `ActiveBot::set_room_prefix()`)
+    /// Default: "!"
+    pub fn set_cmd_prefix(&mut self, prefix: &str) {
+        self.cmd_prefix = prefix.to_string();
+    }
+
+    /// The message, with which the user cancels an active dialog
+    /// Default: "cancel"
+    pub fn set_cancel_keyword(&mut self, keyword: &str) {
+        self.cancel_keyword = keyword.to_string();
+    }
+
+    /// After which time without an answer of the user, a dialog is dropped
+    /// Default: 5 minutes
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Register dialogs
+    /// * command: For which command (excluding the prefix!) the dialog should be started
+    /// * start:   The first step, which is called with the message containing the command
+    pub fn register_dialog(&mut self, command: &str, start: DialogStep) {
+        self.dialogs.insert(command.to_string(), start);
+    }
+
+    /// Whether the given user has an active dialog in the given room
+    pub fn has_dialog(&self, room_id: &str, user_id: &str) -> bool {
+        self.active
+            .get(&(room_id.to_string(), user_id.to_string()))
+            .map(|dialog| dialog.last_activity.elapsed() < self.timeout)
+            .unwrap_or(false)
+    }
+
+    fn run_step(
+        &mut self,
+        key: (String, String),
+        step: DialogStep,
+        mut data: JsonValue,
+        bot: &ActiveBot,
+        message: &Message,
+    ) {
+        match step(bot, message, &mut data) {
+            DialogResult::Next(next) => {
+                let dialog = Dialog {
+                    next,
+                    data,
+                    last_activity: Instant::now(),
+                };
+                self.active.insert(key, dialog);
+            }
+            DialogResult::Done => {
+                debug!(target: "matrix_bot_api::handler", "Dialog done");
+            }
+        }
+    }
+}
+
+impl MessageHandler for ConversationHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let timeout = self.timeout;
+        self.active
+            .retain(|_, dialog| dialog.last_activity.elapsed() < timeout);
+
+        let key = (message.room.clone(), message.sender.clone());
+
+        if let Some(dialog) = self.active.remove(&key) {
+            if message.body.trim() == self.cancel_keyword {
+                debug!(target: "matrix_bot_api::handler", "Dialog cancelled");
+                bot.send_message("Cancelled.", &message.room, MessageType::RoomNotice);
+                return HandleResult::StopHandling;
+            }
+            self.run_step(key, dialog.next, dialog.data, bot, message);
+            return HandleResult::StopHandling;
+        }
+
+        let prefix = bot
+            .room_prefix(&message.room)
+            .unwrap_or_else(|| self.cmd_prefix.clone());
+        let start = match extract_command(&message.body, &prefix) {
+            Some(command) => match self.dialogs.get(command) {
+                Some(start) => *start,
+                None => return HandleResult::ContinueHandling,
+            },
+            None => return HandleResult::ContinueHandling,
+        };
+        debug!(target: "matrix_bot_api::handler", "Starting dialog");
+        self.run_step(
+            key,
+            start,
+            JsonValue::Object(Default::default()),
+            bot,
+            message,
+        );
+        HandleResult::StopHandling
+    }
+
+    fn expects_message(&self, message: &Message) -> bool {
+        self.has_dialog(&message.room, &message.sender)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Default for ConversationHandler {
+    fn default() -> ConversationHandler {
+        ConversationHandler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::StatelessHandler;
+    use crate::MatrixBot;
+    use fractal_matrix_api::backend::BKCommand;
+    use serde_json::json;
+    use std::sync::mpsc::{channel, Receiver};
+
+    const ROOM: &str = "!room:example.org";
+    const OTHER_ROOM: &str = "!other:example.org";
+    const ALICE: &str = "@alice:example.org";
+    const BOB: &str = "@bob:example.org";
+
+    fn start(_: &ActiveBot, _: &Message, data: &mut JsonValue) -> DialogResult {
+        data["answers"] = json!(0);
+        DialogResult::Next(answer)
+    }
+
+    fn answer(_: &ActiveBot, message: &Message, data: &mut JsonValue) -> DialogResult {
+        data["answers"] = json!(data["answers"].as_u64().unwrap_or_default() + 1);
+        match message.body.as_str() {
+            "done" => DialogResult::Done,
+            _ => DialogResult::Next(answer),
+        }
+    }
+
+    /// A bot without homeserver, whose messages are kept in the returned receiver
+    fn bot() -> (ActiveBot, Receiver<BKCommand>) {
+        let mut bot = MatrixBot::new(StatelessHandler::new()).get_activebot_clone();
+        let (backend, sent) = channel();
+        bot.backend = backend;
+        bot.cache_room_config(ROOM, json!({}));
+        bot.cache_room_config(OTHER_ROOM, json!({ "prefix": "?" }));
+        (bot, sent)
+    }
+
+    fn handler() -> ConversationHandler {
+        let mut handler = ConversationHandler::new();
+        handler.register_dialog("ask", start);
+        handler
+    }
+
+    fn message(room: &str, sender: &str, body: &str) -> Message {
+        Message::new(
+            room.to_string(),
+            sender.to_string(),
+            body.to_string(),
+            "m.text".to_string(),
+        )
+    }
+
+    fn answers(handler: &ConversationHandler, room: &str, sender: &str) -> Option<u64> {
+        let key = (room.to_string(), sender.to_string());
+        handler.active.get(&key)?.data["answers"].as_u64()
+    }
+
+    fn is_stop(result: HandleResult) -> bool {
+        match result {
+            HandleResult::StopHandling => true,
+            HandleResult::ContinueHandling => false,
+        }
+    }
+
+    #[test]
+    fn dialogs_are_kept_per_room_and_user() {
+        let (bot, _) = bot();
+        let mut handler = handler();
+        assert!(!is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "hi"))
+        ));
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "!ask"))
+        ));
+        assert_eq!(answers(&handler, ROOM, ALICE), Some(0));
+
+        assert!(handler.expects_message(&message(ROOM, ALICE, "yes")));
+        assert!(!handler.expects_message(&message(ROOM, BOB, "yes")));
+        assert!(!handler.expects_message(&message(OTHER_ROOM, ALICE, "yes")));
+        assert!(!is_stop(
+            handler.handle_message(&bot, &message(ROOM, BOB, "yes"))
+        ));
+        assert_eq!(answers(&handler, ROOM, ALICE), Some(0));
+
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "yes"))
+        ));
+        assert_eq!(answers(&handler, ROOM, ALICE), Some(1));
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "done"))
+        ));
+        assert!(!handler.has_dialog(ROOM, ALICE));
+        assert!(!is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "yes"))
+        ));
+    }
+
+    #[test]
+    fn room_prefix() {
+        let (bot, _) = bot();
+        let mut handler = handler();
+        assert!(!is_stop(
+            handler.handle_message(&bot, &message(OTHER_ROOM, ALICE, "!ask"))
+        ));
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(OTHER_ROOM, ALICE, "?ask"))
+        ));
+        assert!(handler.has_dialog(OTHER_ROOM, ALICE));
+    }
+
+    #[test]
+    fn timeout() {
+        let (bot, _) = bot();
+        let mut handler = handler();
+        handler.set_timeout(Duration::from_secs(0));
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "!ask"))
+        ));
+        assert!(!handler.has_dialog(ROOM, ALICE));
+        assert!(!handler.expects_message(&message(ROOM, ALICE, "yes")));
+        assert!(!is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, "yes"))
+        ));
+        assert!(handler.active.is_empty());
+    }
+
+    #[test]
+    fn cancel() {
+        let (bot, sent) = bot();
+        let mut handler = handler();
+        handler.set_cancel_keyword("stop");
+        handler.handle_message(&bot, &message(ROOM, ALICE, "!ask"));
+        assert!(is_stop(
+            handler.handle_message(&bot, &message(ROOM, ALICE, " stop "))
+        ));
+        assert!(!handler.has_dialog(ROOM, ALICE));
+        match sent.try_recv() {
+            Ok(BKCommand::SendMsg(m)) => {
+                assert_eq!((m.room.as_str(), m.body.as_str()), (ROOM, "Cancelled."))
+            }
+            other => panic!("Expected the cancel message, got {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use crate::handlers::{extract_command, HandleResult, Message, MessageHandler};
 use crate::ActiveBot;
 use std::collections::HashMap;
+use tracing::debug;
 
 /// Convenience-handler that can quickly register and call functions
 /// without any state (each function-call will result in the same output)
 pub struct StatelessHandler {
+    name: String,
     cmd_prefix: String,
     cmd_handles: HashMap<String, fn(&ActiveBot, &Message, &str) -> HandleResult>,
 }
@@ -12,12 +14,21 @@ pub struct StatelessHandler {
 impl StatelessHandler {
     pub fn new() -> StatelessHandler {
         StatelessHandler {
+            name: "StatelessHandler".to_string(),
             cmd_prefix: "!".to_string(),
             cmd_handles: HashMap::new(),
         }
     }
 
-    /// With what prefix commands to the bot will start
+    /// The name of this handler, used in logs and to enable or disable it per room.
+    /// Give each StatelessHandler of a bot its own name (e.g. "dice").
+    /// Default: "StatelessHandler"
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// With what prefix commands to the bot will start,
+    /// unless the room has its own prefix (see `ActiveBot::set_room_prefix()`)
     /// Default: "!"
     pub fn set_cmd_prefix(&mut self, prefix: &str) {
         self.cmd_prefix = prefix.to_string();
@@ -47,21 +58,28 @@ impl StatelessHandler {
 
 impl MessageHandler for StatelessHandler {
     fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        match extract_command(&message.body, &self.cmd_prefix) {
+        let prefix = bot
+            .room_prefix(&message.room)
+            .unwrap_or_else(|| self.cmd_prefix.clone());
+        match extract_command(&message.body, &prefix) {
             Some(command) => {
                 let func = self.cmd_handles.get(command).map(|x| *x);
                 match func {
                     Some(func) => {
-                        if bot.verbose {
-                            println!("Found handle for command \"{}\". Calling it.", &command);
-                        }
-                        let end_of_prefix = self.cmd_prefix.len() + command.len();
+                        debug!(
+                            target: "matrix_bot_api::handler",
+                            "Found handle for command \"{}\". Calling it.",
+                            &command
+                        );
+                        let end_of_prefix = prefix.len() + command.len();
                         func(bot, message, &message.body[end_of_prefix..])
                     }
                     None => {
-                        if bot.verbose {
-                            println!("Command \"{}\" not found in registered handles", &command);
-                        }
+                        debug!(
+                            target: "matrix_bot_api::handler",
+                            "Command \"{}\" not found in registered handles",
+                            &command
+                        );
                         HandleResult::ContinueHandling
                     }
                 }
@@ -71,4 +89,8 @@ impl MessageHandler for StatelessHandler {
             }
         }
     }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
 }
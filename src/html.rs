@@ -0,0 +1,368 @@
+//! Safe HTML for messages that contain user-influenced text.
+//!
+//! * [`escape`] escapes text, so that it can be put into HTML
+//! * [`sanitize`] removes all tags and attributes, that Matrix clients do not allow
+//! * [`neutralize_mentions`] prevents `@room` and user-ids from notifying anyone
+//! * [`Template`] fills placeholders with escaped values
+//!
+//! `ActiveBot::send_sanitized_html_message()` sanitizes the HTML before sending it,
+//! `ActiveBot::send_html_message()` sends it as it is.
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::html::Template;
+//! use matrix_bot_api::{ActiveBot, MessageType};
+//! use serde_json::json;
+//! # fn f(bot: &ActiveBot, room: &str, user_input: &str) {
+//! let template =
+//!     Template::new("Echo: {{text}}", "<b>Echo:</b> {{text}}").neutralize_mentions(true);
+//! let (plain, html) = template.render(&json!({ "text": user_input }));
+//! bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+//! # }
+//! ```
+//!
+//! [`escape`]: fn.escape.html
+//! [`sanitize`]: fn.sanitize.html
+//! [`neutralize_mentions`]: fn.neutralize_mentions.html
+//! [`Template`]: struct.Template.html
+use ammonia::Builder;
+use serde_json::value::Value as JsonValue;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// The tags Matrix clients should allow
+/// (see the client-server-API, section "m.room.message msgtypes")
+const ALLOWED_TAGS: &[&str] = &[
+    "font",
+    "del",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "p",
+    "a",
+    "ul",
+    "ol",
+    "sup",
+    "sub",
+    "li",
+    "b",
+    "i",
+    "u",
+    "strong",
+    "em",
+    "strike",
+    "code",
+    "hr",
+    "br",
+    "div",
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "th",
+    "td",
+    "caption",
+    "pre",
+    "span",
+    "img",
+    "details",
+    "summary",
+    "mx-reply",
+];
+
+/// The attributes Matrix clients should allow, per tag
+const ALLOWED_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("font", &["data-mx-bg-color", "data-mx-color", "color"]),
+    (
+        "span",
+        &["data-mx-bg-color", "data-mx-color", "data-mx-spoiler"],
+    ),
+    ("a", &["name", "target", "href"]),
+    ("img", &["width", "height", "alt", "title", "src"]),
+    ("ol", &["start"]),
+    ("code", &["class"]),
+];
+
+/// The URL schemes Matrix clients should allow in links (and mxc for images)
+const ALLOWED_SCHEMES: &[&str] = &["https", "http", "ftp", "mailto", "magnet", "mxc"];
+
+/// A word joiner (invisible), that breaks mentions without changing how they look
+const WORD_JOINER: char = '\u{2060}';
+
+/// Escapes the given text, so that it is shown as it is, when it is part of HTML
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Removes all tags and attributes from the given HTML, that are not allowed by the
+/// Matrix specification. Images have to be on the homeserver (mxc://-urls) and
+/// code blocks only keep classes like "language-rust".
+pub fn sanitize(html: &str) -> String {
+    builder(false).clean(html).to_string()
+}
+
+/// Like `sanitize()`, but additionally turns links to users (pills) into plain text
+/// and breaks `@room` and user-ids in the text (see `neutralize_mentions()`).
+/// Attributes (e.g. the URLs of links) are left alone.
+pub fn sanitize_without_mentions(html: &str) -> String {
+    let html = builder(true).clean(html).to_string();
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+    // Sanitized HTML escapes "<" in text, so every "<" starts a tag
+    while let Some(start) = rest.find('<') {
+        result.push_str(&neutralize_mentions(&rest[..start]));
+        let end = start + tag_len(&rest[start..]);
+        result.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    result.push_str(&neutralize_mentions(rest));
+    result
+}
+
+/// The length of the tag at the start of the given HTML.
+/// Attribute values are always quoted with '"' and may contain '>'.
+fn tag_len(html: &str) -> usize {
+    let mut quoted = false;
+    for (i, c) in html.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '>' if !quoted => return i + 1,
+            _ => (),
+        }
+    }
+    html.len()
+}
+
+/// Breaks `@room` and user-ids in the given (plain or HTML) text with an invisible
+/// character, so that clients do not notify anyone. E-mail addresses are left alone.
+/// Users mentioned by their display name are still notified.
+pub fn neutralize_mentions(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut previous = ' ';
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        result.push(c);
+        // Only an @ at the start of a word starts a mention
+        if c == '@' && !previous.is_alphanumeric() {
+            if let Some(next) = chars.peek() {
+                if next.is_alphanumeric() || "._=-/".contains(*next) {
+                    result.push(WORD_JOINER);
+                }
+            }
+        }
+        previous = c;
+    }
+    result
+}
+
+fn builder(without_pills: bool) -> Builder<'static> {
+    let tag_attributes: HashMap<&str, HashSet<&str>> = ALLOWED_ATTRIBUTES
+        .iter()
+        .map(|(tag, attributes)| (*tag, attributes.iter().cloned().collect()))
+        .collect();
+
+    let mut builder = Builder::new();
+    builder
+        .tags(ALLOWED_TAGS.iter().cloned().collect())
+        .tag_attributes(tag_attributes)
+        .generic_attributes(HashSet::new())
+        .url_schemes(ALLOWED_SCHEMES.iter().cloned().collect())
+        .link_rel(None)
+        .attribute_filter(move |element, attribute, value| {
+            filter_attribute(element, attribute, value, without_pills)
+        });
+    builder
+}
+
+fn filter_attribute<'u>(
+    element: &str,
+    attribute: &str,
+    value: &'u str,
+    without_pills: bool,
+) -> Option<Cow<'u, str>> {
+    match (element, attribute) {
+        ("img", "src") if !value.starts_with("mxc://") => None,
+        ("code", "class") => {
+            let languages: Vec<&str> = value
+                .split_whitespace()
+                .filter(|class| class.starts_with("language-"))
+                .collect();
+            if languages.is_empty() {
+                None
+            } else {
+                Some(Cow::Owned(languages.join(" ")))
+            }
+        }
+        ("a", "href") if without_pills && value.starts_with("https://matrix.to/#/@") => None,
+        _ => Some(Cow::Borrowed(value)),
+    }
+}
+
+/// A message with placeholders, that are filled with escaped values.
+///
+/// Placeholders look like `{{some.path.0.value}}` and are replaced by the value at this
+/// path in the given JSON values (here `values["some"]["path"][0]["value"]`).
+/// Unknown paths are replaced by an empty string.
+#[derive(Clone)]
+pub struct Template {
+    plain: String,
+    html: String,
+    neutralize_mentions: bool,
+}
+
+impl Template {
+    /// * plain: The template of the plain text body
+    /// * html:  The template of the HTML body. Values are escaped and the result sanitized.
+    pub fn new(plain: &str, html: &str) -> Template {
+        Template {
+            plain: plain.to_string(),
+            html: html.to_string(),
+            neutralize_mentions: false,
+        }
+    }
+
+    /// Neutralize `@room` and user-ids in the values (see `neutralize_mentions()`),
+    /// so that users can not make the bot notify others.
+    /// Default: false
+    pub fn neutralize_mentions(mut self, neutralize: bool) -> Template {
+        self.neutralize_mentions = neutralize;
+        self
+    }
+
+    /// Fills the placeholders and returns the plain text and the HTML body
+    pub fn render(&self, values: &JsonValue) -> (String, String) {
+        let plain = self.fill(&self.plain, values, false);
+        let html = sanitize(&self.fill(&self.html, values, true));
+        (plain, html)
+    }
+
+    fn fill(&self, template: &str, values: &JsonValue, escape_values: bool) -> String {
+        let mut result = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(x) => start + x,
+                None => break,
+            };
+            result.push_str(&rest[..start]);
+
+            let pointer: String = rest[start + 2..end]
+                .trim()
+                .split('.')
+                .map(|key| format!("/{}", key))
+                .collect();
+            let mut value = match values.pointer(&pointer) {
+                Some(JsonValue::String(s)) => s.clone(),
+                Some(JsonValue::Null) | None => String::new(),
+                Some(x) => x.to_string(),
+            };
+            if self.neutralize_mentions {
+                value = neutralize_mentions(&value);
+            }
+            if escape_values {
+                value = escape(&value);
+            }
+            result.push_str(&value);
+
+            rest = &rest[end + 2..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sanitized() {
+        assert_eq!(
+            sanitize("<b>bold</b><script>alert(1)</script>"),
+            "<b>bold</b>"
+        );
+        assert_eq!(
+            sanitize("<a href=\"javascript:alert(1)\" onclick=\"x\">link</a>"),
+            "<a>link</a>"
+        );
+        assert_eq!(
+            sanitize("<img src=\"https://example.org/a.png\"><img src=\"mxc://example.org/a\">"),
+            "<img><img src=\"mxc://example.org/a\">"
+        );
+        assert_eq!(
+            sanitize("<pre><code class=\"language-rust evil\">fn</code></pre>"),
+            "<pre><code class=\"language-rust\">fn</code></pre>"
+        );
+        assert_eq!(
+            sanitize_without_mentions("<a href=\"https://matrix.to/#/@a:x\">A</a> @room"),
+            format!("<a>A</a> @{}room", WORD_JOINER)
+        );
+    }
+
+    #[test]
+    fn attributes_keep_their_mentions() {
+        let link = "<a href=\"https://example.org/@alice:example.org?a>b\">@alice:example.org</a>";
+        assert_eq!(
+            sanitize_without_mentions(link),
+            format!(
+                "<a href=\"https://example.org/@alice:example.org?a>b\">@{}alice:example.org</a>",
+                WORD_JOINER
+            )
+        );
+        assert_eq!(
+            sanitize_without_mentions("<img alt=\"@room\" src=\"mxc://x/a\"> 1 &lt; 2 @room"),
+            format!(
+                "<img alt=\"@room\" src=\"mxc://x/a\"> 1 &lt; 2 @{}room",
+                WORD_JOINER
+            )
+        );
+    }
+
+    #[test]
+    fn mentions_are_neutralized() {
+        assert_eq!(
+            neutralize_mentions("@room and @alice:example.org"),
+            format!("@{}room and @{}alice:example.org", WORD_JOINER, WORD_JOINER)
+        );
+        // E-mail addresses and single @ are left alone
+        assert_eq!(
+            neutralize_mentions("bob@example.org @ x"),
+            "bob@example.org @ x"
+        );
+    }
+
+    #[test]
+    fn template_is_filled() {
+        let template = Template::new(
+            "{{user.name}} said {{ text }}{{missing}} ({{count}})",
+            "<b>{{user.name}}</b> said {{text}}",
+        );
+        let values = json!({
+            "user": { "name": "<Alice>" },
+            "text": "@room <script>",
+            "count": 3,
+        });
+        let (plain, html) = template.render(&values);
+        assert_eq!(plain, "<Alice> said @room <script> (3)");
+        assert_eq!(html, "<b>&lt;Alice&gt;</b> said @room &lt;script&gt;");
+
+        let (plain, _) = template.neutralize_mentions(true).render(&values);
+        assert_eq!(
+            plain,
+            format!("<Alice> said @{}room <script> (3)", WORD_JOINER)
+        );
+        assert_eq!(
+            Template::new("{{a}} {{b", "").render(&json!({"a": 1})).0,
+            "1 {{b"
+        );
+    }
+}
@@ -10,7 +10,8 @@
 //! respond to the message.
 //!
 //! You can write your own MessageHandler by implementing the [`MessageHandler`]-trait,
-//! or use one provided by this crate (currently only [`StatelessHandler`]).
+//! or use one provided by this crate ([`StatelessHandler`] for simple commands,
+//! [`ConversationHandler`] for commands that ask follow-up questions).
 //!
 //! # Multple Handlers:
 //! One can register multiple MessageHandlers with a bot. Thus one can "plug and play"
@@ -18,6 +19,57 @@
 //! Messages are given to each handler in the order of their registration.
 //! A message is given to the next handler until one handler returns `StopHandling`.
 //! Thus a message can be handled by multiple handlers as well (for example for "help").
+//! Only a message a handler waits for (e.g. an answer in a dialog, see
+//! `MessageHandler::expects_message()`) goes straight to that handler.
+//!
+//! # Per-room configuration
+//! Handlers can be enabled or disabled per room, and each room can have its own command
+//! prefix and settings (see [`RoomConfig`]). The configuration is stored as room account
+//! data of the bot. With [`MatrixBot::set_room_commands`], room admins can change it with
+//! built-in commands like `!disable dice`.
+//!
+//! # Room state
+//! [`ActiveBot::room_state`] tells handlers the name, topic and members of a room (see
+//! [`RoomState`]), e.g. to address users by their display name. The state is fetched once per
+//! room and then kept up to date by the sync.
+//!
+//! # Direct messages
+//! [`ActiveBot::dm`] sends a message to a user in a direct chat, which is created if there is
+//! none yet (e.g. for private alerts or to answer privately instead of spamming a room).
+//! [`ActiveBot::is_direct`] tells handlers whether a message came from a direct chat.
+//!
+//! # Formatting
+//! [`ActiveBot::send_markdown`] renders Markdown to HTML, which is sanitized to the tags Matrix
+//! allows, like the HTML of [`ActiveBot::send_sanitized_html_message`]. The [`html`] module
+//! helps to put user input into messages safely (escaping, templates and neutralizing `@room`). The [`mentions`] module builds
+//! pills and finds the users, rooms and events an incoming message refers to.
+//!
+//! # Polls
+//! [`ActiveBot::start_poll`] starts a poll (in the format of MSC3381, which most clients show,
+//! with a text fallback for the others). The votes in all polls are given to
+//! `handle_poll_response()` of the handlers as they arrive, [`ActiveBot::end_poll`] tallies
+//! them and posts the results. The polls the bot has not ended yet are stored on the
+//! homeserver (see [`ActiveBot::open_polls`]).
+//! As the bot does not support encryption, votes in encrypted rooms never reach the handlers
+//! and polls there can not be tallied.
+//!
+//! # Multiple bots
+//! A [`Supervisor`] runs several bots with their own credentials and handlers in one
+//! process. It restarts bots that failed and shuts all of them down together.
+//!
+//! # Encryption
+//! The bot does not support end-to-end encryption, as the backend of fractal does not.
+//! Encrypted messages are never given to the handlers and messages sent by the bot are
+//! not encrypted. The bot logs a warning for each encrypted room it is in,
+//! and [`ActiveBot::is_encrypted`] tells handlers whether a room is encrypted.
+//! For the same reason, the device of the bot can not be verified and cross-signing is not
+//! supported.
+//!
+//! # Shutdown
+//! [`ActiveBot::shutdown`] (or SIGINT/SIGTERM, see [`MatrixBot::set_handle_signals`]) stops the bot
+//! gracefully: Each handler gets its `shutdown_handler()` called, the bot optionally says goodbye
+//! or leaves all its rooms (see [`ShutdownMode`]), and waits for outgoing messages to be sent,
+//! before [`MatrixBot::run`] returns.
 //!
 //! # Example
 //! ```
@@ -28,8 +80,8 @@
 //! fn main() {
 //!     let mut handler = StatelessHandler::new();
 //!     handler.register_handle("shutdown", |bot, _, _| {
-//!         bot.shutdown();
-//!         HandleResult::ContinueHandling /* Other handlers might need to clean up after themselves on shutdown */
+//!         bot.shutdown(); /* All handlers get their shutdown_handler() called */
+//!         HandleResult::StopHandling
 //!     });
 //!
 //!     handler.register_handle("echo", |bot, message, tail| {
@@ -43,9 +95,76 @@
 //! ```
 //! Have a look in the examples/ directory for detailed examples.
 //!
+//! # Logging
+//! All diagnostics are emitted through the [`tracing`] ecosystem, nothing is printed to stdout.
+//! Install any subscriber (e.g. `tracing_subscriber::fmt::init()`) to see them.
+//! The following targets are used:
+//!  * `matrix_bot_api::sync`:     Login, sync-responses and room-invites
+//!  * `matrix_bot_api::send`:     Outgoing messages
+//!  * `matrix_bot_api::dispatch`: Incoming messages and the handler chain (span `message`
+//!    with fields `room`, `event_id` and `sender`)
+//!  * `matrix_bot_api::handler`:  Calls into a single handler (span `handler` with field `name`)
+//!  * `matrix_bot_api::rooms`:    Room administration (creating rooms, kicks, bans, ...)
+//!  * `matrix_bot_api::profile`:  Changes of the display name and avatar of the bot
+//!  * `matrix_bot_api::supervisor`: Starting and restarting bots of a [`Supervisor`]
+//!  * `matrix_bot_api::metrics`:  The metrics-listener (only with the `metrics`-feature)
+//!  * `matrix_bot_api::webhook`:  The webhook-listener (only with the `webhook`-feature)
+//!  * `matrix_bot_api::appservice`: Transactions of the homeserver (only with the
+//!    `appservice`-feature)
+//!
+//! # Metrics
+//! With the optional `metrics`-feature enabled, the bot counts syncs, sync errors, received
+//! messages per room, handler invocations and latency per handler and sent or failed messages.
+//! Use [`MatrixBot::set_metrics_listener`] to expose them in the Prometheus text format.
+//!
+//! # Webhooks
+//! With the optional `webhook`-feature enabled, the bot can run a small HTTP server
+//! that relays incoming webhooks into rooms. See the [`webhook`] module.
+//!
+//! # Configuration files
+//! With the optional `botconfig`-feature enabled, a [`BotConfig`] loads the credentials and
+//! settings of a bot (admins, invite policy, rate limits, per-room defaults, ...) from a TOML,
+//! YAML or JSON file and from environment variables, and builds a ready [`MatrixBot`].
+//! With [`MatrixBot::watch_config_file`], the bot reloads the file whenever it changes,
+//! without a restart.
+//!
+//! # Plugins
+//! With the optional `plugins`-feature enabled, handlers can be loaded from shared libraries
+//! at runtime, without recompiling the bot. See the [`plugin`] module.
+//!
+//! # Application services
+//! With the optional `appservice`-feature enabled, the bot can run as an application service
+//! (e.g. for bridges) instead of a regular user. See the [`appservice`] module.
+//!
+//! [`tracing`]: https://docs.rs/tracing
 //! [`MatrixBot`]: struct.MatrixBot.html
+//! [`MatrixBot::run`]: struct.MatrixBot.html#method.run
+//! [`MatrixBot::set_handle_signals`]: struct.MatrixBot.html#method.set_handle_signals
+//! [`ActiveBot::shutdown`]: struct.ActiveBot.html#method.shutdown
+//! [`ActiveBot::is_encrypted`]: struct.ActiveBot.html#method.is_encrypted
+//! [`ShutdownMode`]: enum.ShutdownMode.html
+//! [`Supervisor`]: struct.Supervisor.html
+//! [`ActiveBot::start_poll`]: struct.ActiveBot.html#method.start_poll
+//! [`ActiveBot::end_poll`]: struct.ActiveBot.html#method.end_poll
+//! [`RoomConfig`]: struct.RoomConfig.html
+//! [`MatrixBot::set_room_commands`]: struct.MatrixBot.html#method.set_room_commands
+//! [`ActiveBot::room_state`]: struct.ActiveBot.html#method.room_state
+//! [`ActiveBot::is_direct`]: struct.ActiveBot.html#method.is_direct
+//! [`ActiveBot::dm`]: struct.ActiveBot.html#method.dm
+//! [`RoomState`]: struct.RoomState.html
+//! [`ActiveBot::send_markdown`]: struct.ActiveBot.html#method.send_markdown
+//! [`ActiveBot::send_sanitized_html_message`]: struct.ActiveBot.html#method.send_sanitized_html_message
+//! [`html`]: html/index.html
+//! [`mentions`]: mentions/index.html
+//! [`MatrixBot::set_metrics_listener`]: struct.MatrixBot.html#method.set_metrics_listener
+//! [`webhook`]: webhook/index.html
+//! [`appservice`]: appservice/index.html
+//! [`plugin`]: plugin/index.html
+//! [`BotConfig`]: struct.BotConfig.html
+//! [`MatrixBot::watch_config_file`]: struct.MatrixBot.html#method.watch_config_file
 //! [`ActiveBot`]: struct.ActiveBot.html
 //! [`MessageHandler`]: handlers/trait.MessageHandler.html
+//! [`ConversationHandler`]: handlers/conversation_handler/struct.ConversationHandler.html
 //! [`StatelessHandler`]: handlers/stateless_handler/struct.StatelessHandler.html
 use chrono::prelude::*;
 
@@ -55,15 +174,65 @@ use serde_json::value::Value as JsonValue;
 use fractal_matrix_api::backend::BKCommand;
 use fractal_matrix_api::backend::BKResponse;
 use fractal_matrix_api::backend::Backend;
+use fractal_matrix_api::backend::BackendData;
+pub use fractal_matrix_api::error::Error;
 use fractal_matrix_api::types::message::get_txn_id;
 pub use fractal_matrix_api::types::{Message, Room};
+use fractal_matrix_api::util::{build_url, encode_uid, json_q, media_url, put_media};
 
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::sync::mpsc::channel;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, debug_span, error, info, info_span, trace, warn};
 
 pub mod handlers;
+pub mod html;
+pub mod markdown;
+pub mod mentions;
 use handlers::{HandleResult, MessageHandler};
 
+mod power_levels;
+pub use power_levels::{PowerLevels, RoomAction};
+mod room_management;
+pub use room_management::RoomOptions;
+mod activity;
+pub use activity::TypingGuard;
+mod direct;
+mod persistent_rooms;
+mod polls;
+pub use polls::{PollAnswer, PollKind, PollResponse, PollResults};
+mod profile;
+pub use profile::{Presence, Profile};
+mod room_config;
+pub use room_config::RoomConfig;
+mod room_state;
+pub use room_state::RoomState;
+mod settings;
+pub use settings::{InvitePolicy, RateLimit};
+mod supervisor;
+pub use supervisor::Supervisor;
+mod sync;
+
+#[cfg(feature = "appservice")]
+pub mod appservice;
+#[cfg(feature = "botconfig")]
+mod bot_config;
+#[cfg(feature = "botconfig")]
+pub use bot_config::{
+    BotConfig, ConfigError, PluginLibrary, RateLimitConfig, RoomEntry, RunError, WebhookEntry,
+};
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
 /// How messages from the bot should be formatted. This is up to the client,
 /// but usually RoomNotice's have a different color than TextMessage's.
 pub enum MessageType {
@@ -72,13 +241,73 @@ pub enum MessageType {
     Image,
 }
 
+/// What the bot does with its rooms, when it is shut down
+pub enum ShutdownMode {
+    /// Simply stop, staying in all rooms
+    Stay,
+    /// Send the given text as a room notice to all joined rooms
+    Goodbye(String),
+    /// Leave all joined rooms
+    LeaveRooms,
+}
+
+/// How long the bot waits for outgoing messages to be sent, when shutting down
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the loop of the bot waits for
+enum LoopEvent {
+    /// A response of the backend (or of the sync, the signal handler, ...)
+    Backend(Box<BKResponse>),
+    /// The watched configuration file changed (see `bot_config::ConfigFile::watch()`)
+    #[cfg(feature = "botconfig")]
+    ConfigFileChanged,
+}
+
+enum ShutdownState {
+    Running,
+    /// Waiting for outgoing messages until the deadline
+    Flushing(Instant),
+    /// Waiting for the backend to shut down
+    Stopping,
+}
+
 pub struct MatrixBot {
     backend: Sender<BKCommand>,
-    rx: Receiver<BKResponse>,
+    data: Arc<Mutex<BackendData>>,
+    tx: Sender<BKResponse>,
+    rx: Receiver<LoopEvent>,
+    #[cfg(feature = "botconfig")]
+    loop_tx: Sender<LoopEvent>,
     uid: Option<String>,
-    verbose: bool,
     update_read_marker: bool,
+    use_sync: bool,
+    handle_signals: bool,
+    shutdown_mode: ShutdownMode,
+    shutdown_state: ShutdownState,
+    pending_messages: Arc<Mutex<HashSet<String>>>,
+    power_level_cache: power_levels::PowerLevelCache,
+    room_config_cache: room_config::RoomConfigCache,
+    room_state_cache: room_state::RoomStateCache,
+    settings: settings::SharedSettings,
+    /// The settings made in code, the configuration file is applied over them
+    code_settings: settings::Settings,
+    sync_queue: sync::SyncQueue,
+    room_command_prefix: Option<String>,
+    persistent_rooms: Vec<persistent_rooms::PersistentRoom>,
+    rate_limits: HashMap<String, VecDeque<Instant>>,
+    #[cfg(feature = "botconfig")]
+    config_file: Option<bot_config::ConfigFile>,
+    #[cfg(feature = "botconfig")]
+    file_config: Option<BotConfig>,
     handlers: Vec<Box<dyn MessageHandler + Send>>,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<String>,
+    #[cfg(feature = "webhook")]
+    webhook_addr: Option<String>,
+    #[cfg(feature = "webhook")]
+    webhook_routes: Vec<webhook::WebhookRoute>,
 }
 
 impl MatrixBot {
@@ -87,20 +316,56 @@ impl MatrixBot {
     where
         M: handlers::MessageHandler + 'static + Send,
     {
-        let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
-        let bk = Backend::new(tx);
-        // Here it would be ideal to extend fractal_matrix_api in order to be able to give
-        // sync a limit-parameter.
-        // Until then, the workaround is to send "since" of the backend to "now".
-        // Not interested in any messages since login
-        bk.data.lock().unwrap().since = Some(Local::now().to_string());
+        let (tx, backend_rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
+        let (loop_tx, rx) = channel();
+        let bk = Backend::new(tx.clone());
+        // Responses are passed on to the loop, that also waits for other events
+        let forward_tx = loop_tx.clone();
+        thread::spawn(move || {
+            for resp in backend_rx {
+                if forward_tx.send(LoopEvent::Backend(Box::new(resp))).is_err() {
+                    break;
+                }
+            }
+        });
+        // The first sync has no "since" and only loads the state of the rooms, messages
+        // from before the start of the bot are not handled (see `sync::start()`)
         MatrixBot {
+            data: bk.data.clone(),
             backend: bk.run(),
+            tx,
             rx,
+            #[cfg(feature = "botconfig")]
+            loop_tx,
             uid: None,
-            verbose: false,
             update_read_marker: true,
+            use_sync: true,
+            handle_signals: false,
+            shutdown_mode: ShutdownMode::Stay,
+            shutdown_state: ShutdownState::Running,
+            pending_messages: Arc::new(Mutex::new(HashSet::new())),
+            power_level_cache: Arc::new(Mutex::new(HashMap::new())),
+            room_config_cache: Arc::new(Mutex::new(HashMap::new())),
+            room_state_cache: Arc::new(Mutex::new(HashMap::new())),
+            settings: Arc::new(Mutex::new(settings::Settings::default())),
+            code_settings: settings::Settings::default(),
+            sync_queue: Arc::new(Mutex::new(VecDeque::new())),
+            room_command_prefix: None,
+            persistent_rooms: vec![],
+            rate_limits: HashMap::new(),
+            #[cfg(feature = "botconfig")]
+            config_file: None,
+            #[cfg(feature = "botconfig")]
+            file_config: None,
             handlers: vec![Box::new(handler)],
+            #[cfg(feature = "metrics")]
+            metrics: metrics::Metrics::new(),
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+            #[cfg(feature = "webhook")]
+            webhook_addr: None,
+            #[cfg(feature = "webhook")]
+            webhook_routes: vec![],
         }
     }
 
@@ -108,8 +373,15 @@ impl MatrixBot {
     pub fn get_activebot_clone(&self) -> ActiveBot {
         ActiveBot {
             backend: self.backend.clone(),
+            data: self.data.clone(),
+            bot_tx: self.tx.clone(),
             uid: self.uid.clone(),
-            verbose: self.verbose,
+            puppet: None,
+            pending_messages: self.pending_messages.clone(),
+            power_level_cache: self.power_level_cache.clone(),
+            room_config_cache: self.room_config_cache.clone(),
+            room_state_cache: self.room_state_cache.clone(),
+            settings: self.settings.clone(),
         }
     }
 
@@ -123,24 +395,181 @@ impl MatrixBot {
         self.handlers.push(Box::new(handler));
     }
 
-    /// If true, will print all Matrix-message coming in and going out (quite verbose!) to stdout
+    /// Used to print all Matrix-message coming in and going out to stdout.
+    /// This is now done via `tracing` on the `TRACE`-level of the targets
+    /// `matrix_bot_api::sync` and `matrix_bot_api::send`, so this function does nothing.
+    #[deprecated(note = "Enable the TRACE-level of matrix_bot_api in your tracing-subscriber")]
+    pub fn set_verbose(&mut self, _verbose: bool) {}
+
+    /// If true, the bot shuts down gracefully on SIGINT and SIGTERM (Ctrl-C on Windows),
+    /// like when `ActiveBot::shutdown()` is called.
+    /// Only one handler per process can be installed, so enable this for one bot only.
     /// Default: false
-    pub fn set_verbose(&mut self, verbose: bool) {
-        self.verbose = verbose;
+    pub fn set_handle_signals(&mut self, handle_signals: bool) {
+        self.handle_signals = handle_signals;
+    }
+
+    /// What the bot does with its rooms, when it is shut down
+    /// Default: ShutdownMode::Stay
+    pub fn set_shutdown_mode(&mut self, mode: ShutdownMode) {
+        self.shutdown_mode = mode;
     }
 
-    /// If true, bot will continually update its read marker
+    /// If true, bot will continually update its read marker.
+    /// If false, handlers can acknowledge messages with `ActiveBot::mark_read()`.
     /// Default: true
     pub fn set_update_read_marker(&mut self, update_read_marker: bool) {
         self.update_read_marker = update_read_marker;
     }
 
+    /// Enables the built-in commands for room admins, with the given prefix
+    /// (or the prefix of the room, see `ActiveBot::set_room_prefix()`):
+    ///  * `features`:       Lists all handlers and whether they are enabled in the room
+    ///  * `enable <name>`:  Enables the given handler in the room
+    ///  * `disable <name>`: Disables the given handler in the room
+    ///  * `prefix <text>`:  Sets the command prefix of the room (`prefix reset` to reset it)
+    ///  * `reload`:         Reloads the configuration (see `watch_config_file()`) and calls
+    ///    `config_changed()` of all handlers. For admins of the bot only and only with the
+    ///    `botconfig`-feature.
+    ///
+    /// Room admins are users that are allowed to change the power levels of the room
+    /// and the admins of the bot (see `add_admin()`).
+    /// Default: None (disabled)
+    pub fn set_room_commands(&mut self, prefix: Option<&str>) {
+        self.room_command_prefix = prefix.map(String::from);
+    }
+
+    /// Add a room the bot should always be in. The bot joins it after the login and
+    /// rejoins it, when it left or was kicked. Failed joins are retried with a growing
+    /// delay (up to an hour) and invites into the room are always accepted.
+    ///  * room:        The room-id or an alias (e.g. "#foo:your.homeserver")
+    ///  * via_servers: Servers to join through (see `ActiveBot::join_room()`)
+    pub fn add_persistent_room(&mut self, room: &str, via_servers: &[&str]) {
+        let via_servers = via_servers.iter().map(|s| s.to_string()).collect();
+        match self.persistent_rooms.iter_mut().find(|p| p.room == room) {
+            Some(persistent) => {
+                persistent.via_servers = via_servers;
+                persistent.from_file = false;
+            }
+            None => self
+                .persistent_rooms
+                .push(persistent_rooms::PersistentRoom::new(room, via_servers)),
+        }
+    }
+
+    /// Add an admin of the bot. Admins can use the room commands in every room
+    /// (see `set_room_commands()`) and are never rate limited. Handlers can check
+    /// for them with `ActiveBot::is_bot_admin()`.
+    pub fn add_admin(&mut self, user_id: &str) {
+        self.code_settings.admins.insert(user_id.to_string());
+        self.update_settings();
+    }
+
+    /// Which invites the bot accepts
+    /// Default: InvitePolicy::All
+    pub fn set_invite_policy(&mut self, policy: InvitePolicy) {
+        self.code_settings.invite_policy = policy;
+        self.update_settings();
+    }
+
+    /// Limit how many messages each user may send to the handlers.
+    /// Further messages are ignored until the user slows down.
+    /// Default: None (no limit)
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.code_settings.rate_limit = limit;
+        self.update_settings();
+    }
+
+    /// The directory handlers can store their data in (see `ActiveBot::data_dir()`)
+    /// Default: None
+    pub fn set_data_dir(&mut self, path: &Path) {
+        self.code_settings.data_dir = Some(path.to_path_buf());
+        self.update_settings();
+    }
+
+    /// The command prefix of all rooms without their own prefix
+    /// (see `ActiveBot::set_room_prefix()`).
+    /// Default: None (every handler uses its own prefix)
+    pub fn set_default_prefix(&mut self, prefix: Option<&str>) {
+        self.code_settings.default_prefix = prefix.map(String::from);
+        self.update_settings();
+    }
+
+    /// The configuration of the given room, as long as the room admins did not change it
+    /// (see [`RoomConfig`]). It has the same layout as the stored configuration:
+    /// `{"prefix": "!", "disabled": ["dice"], "settings": {"key": "value"}}`
+    ///
+    /// [`RoomConfig`]: struct.RoomConfig.html
+    pub fn set_room_defaults(&mut self, room_id: &str, defaults: JsonValue) {
+        self.code_settings
+            .room_defaults
+            .insert(room_id.to_string(), defaults);
+        self.update_settings();
+    }
+
+    /// Reload the configuration from the given file (see [`BotConfig`]), whenever it changes
+    /// or an admin of the bot sends the room command `reload` (see `set_room_commands()`).
+    /// Everything but the credentials is reloaded and the handlers are notified by
+    /// `config_changed()`. They keep their state and the bot does not lose its sync position.
+    /// The values of the file are applied over the settings made in code, persistent rooms
+    /// removed from the file are no longer rejoined.
+    /// Default: No file is watched
+    ///
+    /// [`BotConfig`]: struct.BotConfig.html
+    #[cfg(feature = "botconfig")]
+    pub fn watch_config_file(&mut self, path: &str) {
+        self.config_file = Some(bot_config::ConfigFile::new(path));
+    }
+
+    /// Serve the metrics of this bot in the Prometheus text format on the given
+    /// address (e.g. "127.0.0.1:9184"). The listener is started by `run()`.
+    /// Default: No listener
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics_listener(&mut self, addr: &str) {
+        self.metrics_addr = Some(addr.to_string());
+    }
+
+    /// Listen for webhooks on the given address (e.g. "0.0.0.0:8080").
+    /// The listener is started by `run()`.
+    /// Default: No listener
+    #[cfg(feature = "webhook")]
+    pub fn set_webhook_listener(&mut self, addr: &str) {
+        self.webhook_addr = Some(addr.to_string());
+    }
+
+    /// Add a route to the webhook-listener.
+    /// Requests to paths without a route are answered with "404 Not Found".
+    #[cfg(feature = "webhook")]
+    pub fn add_webhook_route(&mut self, route: webhook::WebhookRoute) {
+        self.webhook_routes.push(route);
+    }
+
     /// Blocking call that runs as long as the Bot is running.
     /// Will call for each incoming text-message the given MessageHandler.
-    /// Bot will automatically join all rooms it is invited to.
-    /// Will return on shutdown only.
+    /// Bot will automatically join all rooms it is invited to (see `set_invite_policy()`).
+    /// Will return on shutdown only, after all handlers had their `shutdown_handler()` called
+    /// and all outgoing messages have been sent.
     /// All messages prior to run() will be ignored.
-    pub fn run(mut self, user: &str, password: &str, homeserver_url: &str) {
+    /// Panics, if the login fails.
+    pub fn run(self, user: &str, password: &str, homeserver_url: &str) {
+        if let Err(e) = self.try_run(user, password, homeserver_url) {
+            panic!("Error while trying to login: {:#?}", e)
+        }
+    }
+
+    /// Like `run()`, but returns the error if the login fails
+    pub(crate) fn try_run(
+        self,
+        user: &str,
+        password: &str,
+        homeserver_url: &str,
+    ) -> Result<(), Error> {
+        info!(
+            target: "matrix_bot_api::sync",
+            user = user,
+            homeserver_url = homeserver_url,
+            "Logging in"
+        );
         self.backend
             .send(BKCommand::Login(
                 user.to_string(),
@@ -149,49 +578,310 @@ impl MatrixBot {
             ))
             .unwrap();
 
+        self.run_loop()
+    }
+
+    /// Like `run()`, but uses an existing access token instead of logging in with a password
+    pub fn run_with_token(self, user_id: &str, access_token: &str, homeserver_url: &str) {
+        self.set_token(user_id, access_token, homeserver_url);
+        // Only a login with a password can fail
+        let _ = self.run_loop();
+    }
+
+    /// Like `run()`, but runs the bot as an application service instead of logging in.
+    /// The homeserver pushes all events of interest to the HTTP-listener of the
+    /// given application service, which gives them to the same handlers `run()` would.
+    /// Handlers can act as any user in the namespace of the application service,
+    /// using `ActiveBot::as_user()`.
+    #[cfg(feature = "appservice")]
+    pub fn run_appservice(mut self, appservice: appservice::AppService, homeserver_url: &str) {
+        info!(
+            target: "matrix_bot_api::appservice",
+            user = appservice.bot_user_id().as_str(),
+            homeserver_url = homeserver_url,
+            "Starting application service"
+        );
+        // The homeserver sends us all events, there is no need to sync
+        self.use_sync = false;
+        self.backend
+            .send(BKCommand::SetToken(
+                appservice.as_token().to_string(),
+                appservice.bot_user_id(),
+                homeserver_url.to_string(),
+            ))
+            .unwrap();
+        appservice.serve(self.tx.clone(), self.get_activebot_clone());
+
+        // Only a login with a password can fail
+        let _ = self.run_loop();
+    }
+
+    /* --------- Private functions ------------ */
+    fn set_token(&self, user_id: &str, access_token: &str, homeserver_url: &str) {
+        info!(
+            target: "matrix_bot_api::sync",
+            user = user_id,
+            homeserver_url = homeserver_url,
+            "Using access token"
+        );
+        self.backend
+            .send(BKCommand::SetToken(
+                access_token.to_string(),
+                user_id.to_string(),
+                homeserver_url.to_string(),
+            ))
+            .unwrap();
+    }
+
+    fn run_loop(mut self) -> Result<(), Error> {
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(addr) = &self.metrics_addr {
+                self.metrics.serve(addr);
+            }
+        }
+
         let mut active_bot = self.get_activebot_clone();
 
+        #[cfg(feature = "webhook")]
+        {
+            if let Some(addr) = &self.webhook_addr {
+                let routes = std::mem::take(&mut self.webhook_routes);
+                webhook::serve(addr, routes, active_bot.clone());
+            }
+        }
+
+        #[cfg(feature = "botconfig")]
+        {
+            if let Some(file) = &self.config_file {
+                file.watch(self.loop_tx.clone());
+            }
+        }
+
+        if self.handle_signals {
+            let tx = self.tx.clone();
+            let result = ctrlc::set_handler(move || {
+                let _ = tx.send(BKResponse::ShutDown);
+            });
+            if let Err(e) = result {
+                warn!(target: "matrix_bot_api::sync", "Could not install signal handler: {}", e);
+            }
+        }
+
         for handler in self.handlers.iter_mut() {
             handler.init_handler(&active_bot);
         }
 
         loop {
-            let cmd = self.rx.recv().unwrap();
+            let event = match self.shutdown_state {
+                ShutdownState::Flushing(deadline) => {
+                    if self.pending_messages.lock().unwrap().is_empty()
+                        || Instant::now() >= deadline
+                    {
+                        self.finish_shutdown(&active_bot);
+                        continue;
+                    }
+                    match self.rx.recv_timeout(Duration::from_millis(100)) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                _ => self.rx.recv().unwrap(),
+            };
+            let cmd = match event {
+                LoopEvent::Backend(cmd) => *cmd,
+                #[cfg(feature = "botconfig")]
+                LoopEvent::ConfigFileChanged => {
+                    if let ShutdownState::Running = self.shutdown_state {
+                        if self.config_file_changed() {
+                            self.reload_config(&active_bot);
+                        }
+                    }
+                    continue;
+                }
+            };
+            if let BKResponse::LoginError(e) = cmd {
+                error!(target: "matrix_bot_api::sync", "Error while trying to login: {:?}", e);
+                self.backend.send(BKCommand::ShutDown).unwrap();
+                return Err(e);
+            }
             if !self.handle_recvs(cmd, &mut active_bot) {
                 break;
             }
         }
+        Ok(())
     }
 
-    /* --------- Private functions ------------ */
     fn handle_recvs(&mut self, resp: BKResponse, active_bot: &mut ActiveBot) -> bool {
-        if self.verbose {
-            println!("<=== received: {:?}", resp);
-        }
+        trace!(target: "matrix_bot_api::sync", "<=== received: {:?}", resp);
 
         match resp {
-            BKResponse::UpdateRooms(x) => self.handle_rooms(x),
             //BKResponse::Rooms(x, _) => self.handle_rooms(x),
             BKResponse::RoomMessages(x) => self.handle_messages(x, active_bot),
             BKResponse::Token(uid, _, _) => {
+                info!(target: "matrix_bot_api::sync", uid = uid.as_str(), "Logged in");
                 self.uid = Some(uid); // Successful login
                 active_bot.uid = self.uid.clone();
-                self.backend.send(BKCommand::Sync(None, true)).unwrap();
+                self.join_persistent_rooms(active_bot);
+                if self.use_sync {
+                    self.sync(active_bot);
+                }
+            }
+            BKResponse::Sync(_) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.syncs.inc();
+                self.handle_syncs(active_bot);
+                if let ShutdownState::Running = self.shutdown_state {
+                    self.join_persistent_rooms(active_bot);
+                }
+                self.sync(active_bot);
+            }
+            BKResponse::SyncError(err) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.sync_errors.inc();
+                warn!(target: "matrix_bot_api::sync", "Sync failed, retrying: {:?}", err);
+                self.sync(active_bot);
             }
-            BKResponse::Sync(_) => self.backend.send(BKCommand::Sync(None, false)).unwrap(),
-            BKResponse::SyncError(_) => self.backend.send(BKCommand::Sync(None, false)).unwrap(),
-            BKResponse::ShutDown => {
-                return false;
+            BKResponse::ShutDown => match self.shutdown_state {
+                ShutdownState::Running => self.start_shutdown(active_bot),
+                ShutdownState::Flushing(_) => (),
+                ShutdownState::Stopping => {
+                    info!(target: "matrix_bot_api::sync", "Shut down");
+                    return false;
+                }
+            },
+            BKResponse::SentMsg(txn_id, event_id) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.messages_sent.inc();
+                self.pending_messages.lock().unwrap().remove(&txn_id);
+                debug!(
+                    target: "matrix_bot_api::send",
+                    txn_id = txn_id.as_str(),
+                    event_id = event_id.as_str(),
+                    "Message sent"
+                );
+            }
+            BKResponse::SendMsgError(err) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.messages_failed.inc();
+                if let Error::SendMsgError(txn_id) = &err {
+                    self.pending_messages.lock().unwrap().remove(txn_id);
+                }
+                error!(target: "matrix_bot_api::send", "Sending message failed: {:?}", err);
+            }
+            BKResponse::JoinRoomError(err) => {
+                error!(target: "matrix_bot_api::sync", "Joining room failed: {:?}", err);
+            }
+            BKResponse::LeaveRoomError(err) => {
+                error!(target: "matrix_bot_api::sync", "Leaving room failed: {:?}", err);
+            }
+            BKResponse::MarkAsReadError(err) => {
+                warn!(target: "matrix_bot_api::send", "Updating read marker failed: {:?}", err);
             }
-            BKResponse::LoginError(x) => panic!("Error while trying to login: {:#?}", x),
             _ => (),
         }
         true
     }
 
+    fn sync(&self, active_bot: &ActiveBot) {
+        // No new messages for the handlers, once the shutdown has begun
+        if let ShutdownState::Running = self.shutdown_state {
+            sync::start(active_bot, &self.sync_queue, &self.tx);
+        }
+    }
+
+    /// Gives all handlers the chance to clean up and says goodbye, if requested.
+    /// The bot then waits for all outgoing messages, before leaving rooms and
+    /// shutting down the backend (see finish_shutdown()).
+    fn start_shutdown(&mut self, active_bot: &ActiveBot) {
+        info!(target: "matrix_bot_api::sync", "Shutting down");
+        for handler in self.handlers.iter_mut() {
+            let span = debug_span!(
+                target: "matrix_bot_api::handler",
+                "handler",
+                name = handler.name()
+            );
+            let _enter = span.enter();
+            handler.shutdown_handler(active_bot);
+        }
+
+        if let ShutdownMode::Goodbye(text) = &self.shutdown_mode {
+            match active_bot.joined_rooms() {
+                Ok(rooms) => {
+                    for room in rooms {
+                        active_bot.send_message(text, &room, MessageType::RoomNotice);
+                    }
+                }
+                Err(e) => error!(target: "matrix_bot_api::sync", "Could not get rooms: {:?}", e),
+            }
+        }
+
+        self.shutdown_state = ShutdownState::Flushing(Instant::now() + FLUSH_TIMEOUT);
+    }
+
+    fn finish_shutdown(&mut self, active_bot: &ActiveBot) {
+        let unsent = self.pending_messages.lock().unwrap().len();
+        if unsent > 0 {
+            warn!(
+                target: "matrix_bot_api::send",
+                "Shutting down with {} unsent messages",
+                unsent
+            );
+        }
+
+        if let ShutdownMode::LeaveRooms = self.shutdown_mode {
+            match active_bot.joined_rooms() {
+                Ok(rooms) => {
+                    for room in rooms {
+                        info!(target: "matrix_bot_api::sync", room = room.as_str(), "Leaving room");
+                        let url = format!("rooms/{}/leave", encode_uid(&room));
+                        if let Err(e) = active_bot.api("post", &url, &JsonValue::Null) {
+                            error!(target: "matrix_bot_api::sync", "Leaving room failed: {:?}", e);
+                        }
+                    }
+                }
+                Err(e) => error!(target: "matrix_bot_api::sync", "Could not get rooms: {:?}", e),
+            }
+        }
+
+        self.shutdown_state = ShutdownState::Stopping;
+        self.backend.send(BKCommand::ShutDown).unwrap();
+    }
+
+    /// Called for the "m.room.encryption"-state of each room in the sync
+    fn room_encrypted(&self, room_id: &str) {
+        // Encrypted messages never reach the handlers, so at least tell the operator
+        warn!(
+            target: "matrix_bot_api::sync",
+            room = room_id,
+            "Room is end-to-end encrypted. The bot can not read or send messages there"
+        );
+    }
+
     fn handle_messages(&mut self, messages: Vec<Message>, active_bot: &ActiveBot) {
+        // No new messages for the handlers, once the shutdown has begun
+        match self.shutdown_state {
+            ShutdownState::Running => (),
+            _ => return,
+        }
+
         for message in messages {
-            
+            let span = info_span!(
+                target: "matrix_bot_api::dispatch",
+                "message",
+                room = message.room.as_str(),
+                event_id = message.id.as_str(),
+                sender = message.sender.as_str()
+            );
+            let _enter = span.enter();
+
+            #[cfg(feature = "metrics")]
+            self.metrics
+                .messages_received
+                .with_label_values(&[&message.room])
+                .inc();
+
             /* First of all, mark all new messages as "read" */
             if self.update_read_marker {
                 self.backend
@@ -207,23 +897,98 @@ impl MatrixBot {
             let uid = self.uid.clone().unwrap_or_default();
             // This might be a command for us (only text-messages are interesting)
             if message.mtype == "m.text" && message.sender != uid {
-                for handler in self.handlers.iter_mut() {
-                    match handler.handle_message(&active_bot, &message) {
+                if self.is_rate_limited(&message.sender) {
+                    continue;
+                }
+                if self.handle_room_command(&message, active_bot) {
+                    debug!(target: "matrix_bot_api::dispatch", "Handled room command");
+                    continue;
+                }
+
+                let config = match active_bot.room_config(&message.room) {
+                    Ok(config) => Some(config),
+                    Err(e) => {
+                        debug!(
+                            target: "matrix_bot_api::dispatch",
+                            "Could not get room config, enabling all handlers: {:?}",
+                            e
+                        );
+                        None
+                    }
+                };
+
+                // Answers in a dialog go to the handler that asked, and to no other
+                let expecting = self
+                    .handlers
+                    .iter()
+                    .position(|handler| handler.expects_message(&message));
+                if let Some(index) = expecting {
+                    debug!(target: "matrix_bot_api::dispatch", "Dispatching answer to handler");
+                    self.call_handler(index, active_bot, &message);
+                    continue;
+                }
+
+                debug!(target: "matrix_bot_api::dispatch", "Dispatching message to handlers");
+                for index in 0..self.handlers.len() {
+                    if let Some(config) = &config {
+                        let name = self.handlers[index].name();
+                        if !config.is_enabled(name) {
+                            trace!(
+                                target: "matrix_bot_api::dispatch",
+                                handler = name,
+                                "Handler disabled in this room"
+                            );
+                            continue;
+                        }
+                    }
+                    match self.call_handler(index, active_bot, &message) {
                         HandleResult::ContinueHandling => continue,
-                        HandleResult::StopHandling => break,
+                        HandleResult::StopHandling => {
+                            debug!(target: "matrix_bot_api::handler", "Handler stopped handling");
+                            break;
+                        }
                     }
                 }
+            } else {
+                trace!(target: "matrix_bot_api::dispatch", "Ignoring message");
             }
         }
     }
 
-    fn handle_rooms(&self, rooms: Vec<Room>) {
+    /// Gives the message to the handler with the given index
+    fn call_handler(
+        &mut self,
+        index: usize,
+        active_bot: &ActiveBot,
+        message: &Message,
+    ) -> HandleResult {
+        let handler = &mut self.handlers[index];
+        let span = debug_span!(
+            target: "matrix_bot_api::handler",
+            "handler",
+            name = handler.name()
+        );
+        let _enter = span.enter();
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let result = handler.handle_message(active_bot, message);
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .observe_handler(handler.name(), start.elapsed());
+        result
+    }
+
+    fn handle_rooms(&mut self, rooms: Vec<Room>, active_bot: &ActiveBot) {
         for rr in rooms {
-            if rr.membership.is_invited() {
+            self.update_persistent_rooms(&rr, active_bot);
+
+            if rr.membership.is_invited() && !self.accepts_invite(&rr) {
+                info!(target: "matrix_bot_api::sync", room = rr.id.as_str(), "Ignoring invite");
+            } else if rr.membership.is_invited() {
                 self.backend
                     .send(BKCommand::JoinRoom(rr.id.clone()))
                     .unwrap();
-                println!("Joining room {}", rr.id.clone());
+                info!(target: "matrix_bot_api::sync", room = rr.id.as_str(), "Joining room");
             }
         }
     }
@@ -234,17 +999,60 @@ impl MatrixBot {
 #[derive(Clone)]
 pub struct ActiveBot {
     backend: Sender<BKCommand>,
+    data: Arc<Mutex<BackendData>>,
+    bot_tx: Sender<BKResponse>,
     uid: Option<String>,
-    verbose: bool,
+    puppet: Option<String>,
+    pending_messages: Arc<Mutex<HashSet<String>>>,
+    power_level_cache: power_levels::PowerLevelCache,
+    room_config_cache: room_config::RoomConfigCache,
+    room_state_cache: room_state::RoomStateCache,
+    settings: settings::SharedSettings,
 }
 
 impl ActiveBot {
-    /// Will shutdown the bot. The bot will not leave any rooms.
+    /// Will shutdown the bot. All handlers get their `shutdown_handler()` called and
+    /// outgoing messages are sent, before the bot stops.
+    /// The bot will only leave its rooms, if `ShutdownMode::LeaveRooms` is set.
     pub fn shutdown(&self) {
-        self.backend.send(BKCommand::ShutDown).unwrap();
+        self.bot_tx.send(BKResponse::ShutDown).unwrap();
+    }
+
+    /// Whether messages in the given room are end-to-end encrypted.
+    /// The bot can neither read nor write encrypted messages, as the backend
+    /// of fractal does not support encryption.
+    pub fn is_encrypted(&self, room_id: &str) -> Result<bool, Error> {
+        Ok(self.room_state(room_id)?.encrypted)
     }
 
-    /// Will leave the given room (give room-id, not room-name)
+    /// Returns a handle that acts as the given user instead of the bot.
+    /// Only works when running as application service (see `MatrixBot::run_appservice()`)
+    /// and only for users in the namespace of the application service.
+    #[cfg(feature = "appservice")]
+    pub fn as_user(&self, user_id: &str) -> ActiveBot {
+        ActiveBot {
+            uid: Some(user_id.to_string()),
+            puppet: Some(user_id.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the room-ids of all rooms the bot has joined
+    pub fn joined_rooms(&self) -> Result<Vec<String>, Error> {
+        let js = self.api("get", "joined_rooms", &JsonValue::Null)?;
+        Ok(js["joined_rooms"]
+            .as_array()
+            .map(|rooms| {
+                rooms
+                    .iter()
+                    .filter_map(|room| room.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Will leave the given room (give room-id, not room-name or alias,
+    /// see `ActiveBot::resolve_alias()`)
     pub fn leave_room(&self, room_id: &str) {
         self.backend
             .send(BKCommand::LeaveRoom(room_id.to_string()))
@@ -260,6 +1068,8 @@ impl ActiveBot {
         self.raw_send_message(msg, html, None, None, room, msgtype);
     }
     /// Sends an HTML message to a given room, with a given message-type.
+    /// The HTML is sent as it is, use `send_sanitized_html_message()` for HTML that
+    /// contains user input.
     ///  * msg:     The incoming message
     ///  * html:    The html-formatted message
     ///  * room:    The room-id that the message should be sent to
@@ -268,6 +1078,54 @@ impl ActiveBot {
         self.raw_send_message(msg, Some(html), None, None, room, msgtype);
     }
 
+    /// Like `send_html_message()`, but tags and attributes, that are not allowed in Matrix,
+    /// are removed first (see [`html::sanitize`]).
+    ///
+    /// [`html::sanitize`]: html/fn.sanitize.html
+    pub fn send_sanitized_html_message(
+        &self,
+        msg: &str,
+        html: &str,
+        room: &str,
+        msgtype: MessageType,
+    ) {
+        let html = html::sanitize(html);
+        self.raw_send_message(msg, Some(&html), None, None, room, msgtype);
+    }
+
+    /// Like `send_sanitized_html_message()`, but notifies the given users (as `m.mentions`).
+    /// The HTML should contain a pill for each of them (see `mentions::user_pill()`).
+    pub fn send_html_message_with_mentions(
+        &self,
+        msg: &str,
+        html: &str,
+        room: &str,
+        msgtype: MessageType,
+        user_ids: &[&str],
+    ) {
+        let html = html::sanitize(html);
+        let extra_content = json!({ "m.mentions": { "user_ids": user_ids } });
+        self.raw_send_message(msg, Some(&html), None, Some(extra_content), room, msgtype);
+    }
+
+    /// Sends a Markdown (CommonMark) message to a given room, with a given message-type.
+    /// The HTML-body and the plain text fallback are rendered by the [`markdown`]-module.
+    /// Messages without any formatting are sent as plain text.
+    ///  * md:      The Markdown message
+    ///  * room:    The room-id that the message should be sent to
+    ///  * msgtype: Type of message (text or notice)
+    ///
+    /// [`markdown`]: markdown/index.html
+    pub fn send_markdown(&self, md: &str, room: &str, msgtype: MessageType) {
+        let plain = markdown::to_plain(md);
+        if markdown::is_formatted(md) {
+            let html = markdown::to_html(md);
+            self.send_sanitized_html_message(&plain, &html, room, msgtype);
+        } else {
+            self.send_message(&plain, room, msgtype);
+        }
+    }
+
     /// Sends an image to a given room.
     ///  * name: The name of the image
     ///  * url:  The url for the image
@@ -347,10 +1205,139 @@ impl ActiveBot {
             source: None,
         };
 
-        if self.verbose {
-            println!("===> sending: {:?}", m);
+        debug!(
+            target: "matrix_bot_api::send",
+            room = room,
+            txn_id = m.id.as_str(),
+            "Sending message"
+        );
+        trace!(target: "matrix_bot_api::send", "===> sending: {:?}", m);
+
+        // The backend can only send as the bot itself
+        if self.puppet.is_some() {
+            self.puppet_send_message(&m);
+            return;
         }
 
+        self.pending_messages.lock().unwrap().insert(m.id.clone());
         self.backend.send(BKCommand::SendMsg(m)).unwrap();
     }
+
+    /// Uploads the given file (e.g. an image) to the homeserver and returns its mxc://-url,
+    /// to be used for avatars or `send_image()`. This call is blocking.
+    pub fn upload(&self, file: Vec<u8>) -> Result<String, Error> {
+        let (base, token) = {
+            let data = self.data.lock().unwrap();
+            (data.server_url.clone(), data.access_token.clone())
+        };
+        let url = media_url(&base, "upload", &[("access_token", token)])?;
+        let js = put_media(url.as_str(), file)?;
+        match js["content_uri"].as_str() {
+            Some(uri) => Ok(uri.to_string()),
+            None => Err(Error::MatrixError(js)),
+        }
+    }
+
+    /// Sends an event of the given type (e.g. "m.reaction") to the given room and
+    /// returns its event-id. Unlike `send_message()`, this call is blocking.
+    pub fn send_event(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        content: &JsonValue,
+    ) -> Result<String, Error> {
+        let txn_id = get_txn_id(room_id, &content.to_string(), &Local::now().to_string());
+        let path = format!(
+            "rooms/{}/send/{}/{}",
+            encode_uid(room_id),
+            event_type,
+            encode_uid(&txn_id)
+        );
+        debug!(
+            target: "matrix_bot_api::send",
+            room = room_id,
+            event_type = event_type,
+            "Sending event"
+        );
+        let js = self.api("put", &path, content)?;
+        match js["event_id"].as_str() {
+            Some(event_id) => Ok(event_id.to_string()),
+            None => Err(Error::MatrixError(js)),
+        }
+    }
+
+    /// The user-id the bot acts as (the puppet, when running as application service)
+    fn user_id(&self) -> String {
+        match &self.puppet {
+            Some(puppet) => puppet.clone(),
+            None => self.data.lock().unwrap().user_id.clone(),
+        }
+    }
+
+    /// Blocking call of the client-server-API of the homeserver, for everything
+    /// the backend of fractal does not provide.
+    ///  * method: "get", "post", "put" or "delete"
+    ///  * path:   The path relative to "/_matrix/client/r0/"
+    ///  * attrs:  The JSON body of the request (JsonValue::Null for none)
+    fn api(&self, method: &str, path: &str, attrs: &JsonValue) -> Result<JsonValue, Error> {
+        self.versioned_api(method, "r0", path, &[], attrs)
+    }
+
+    /// Like `api()`, for endpoints that only exist in other versions of the API (e.g. "v1")
+    /// or that need query parameters
+    fn versioned_api(
+        &self,
+        method: &str,
+        version: &str,
+        path: &str,
+        params: &[(&str, String)],
+        attrs: &JsonValue,
+    ) -> Result<JsonValue, Error> {
+        let (base, token) = {
+            let data = self.data.lock().unwrap();
+            (data.server_url.clone(), data.access_token.clone())
+        };
+        let mut params = params.to_vec();
+        params.push(("access_token", token));
+        if let Some(puppet) = &self.puppet {
+            params.push(("user_id", puppet.clone()));
+        }
+        let path = format!("/_matrix/client/{}/{}", version, path);
+        let url = build_url(&base, &path, &params)?;
+        json_q(method, &url, attrs)
+    }
+
+    /// Sends the message through the client-server-API, as the backend would do it
+    fn puppet_send_message(&self, m: &Message) {
+        let attrs = message_content(m);
+        let path = format!(
+            "rooms/{}/send/m.room.message/{}",
+            encode_uid(&m.room),
+            encode_uid(&m.id)
+        );
+        if let Err(e) = self.api("put", &path, &attrs) {
+            error!(target: "matrix_bot_api::send", "Sending message failed: {:?}", e);
+        }
+    }
+}
+
+/// The content of the "m.room.message"-event, the backend would send for the message
+fn message_content(m: &Message) -> JsonValue {
+    let mut content = json!({
+        "body": m.body,
+        "msgtype": m.mtype,
+    });
+    if let Some(url) = &m.url {
+        content["url"] = json!(url);
+    }
+    if let (Some(format), Some(formatted_body)) = (&m.format, &m.formatted_body) {
+        content["format"] = json!(format);
+        content["formatted_body"] = json!(formatted_body);
+    }
+    if let Some(extra) = m.extra_content.as_ref().and_then(|x| x.as_object()) {
+        for (k, v) in extra {
+            content[k] = v.clone();
+        }
+    }
+    content
 }
@@ -0,0 +1,184 @@
+//! Rendering of Markdown (CommonMark) for outgoing messages.
+//!
+//! [`to_html`] produces the formatted body, using only tags Matrix clients allow,
+//! [`to_plain`] produces a readable plain text fallback for the body.
+//! Use [`escape`] for user-provided fragments that are put into Markdown,
+//! so that they are shown as they are.
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::{markdown, ActiveBot, MessageType};
+//! # fn f(bot: &ActiveBot, room: &str, user_input: &str) {
+//! let md = format!("**Echo:** {}", markdown::escape(user_input));
+//! bot.send_markdown(&md, room, MessageType::TextMessage);
+//! # }
+//! ```
+//!
+//! [`to_html`]: fn.to_html.html
+//! [`to_plain`]: fn.to_plain.html
+//! [`escape`]: fn.escape.html
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// Renders the given Markdown to HTML.
+/// Raw HTML in the Markdown is escaped and images, which are not on the homeserver
+/// (mxc://-urls), are replaced by links.
+pub fn to_html(md: &str) -> String {
+    let events = parser(md).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Image(link_type, url, title)) if !url.starts_with("mxc://") => {
+            Event::Start(Tag::Link(link_type, url, title))
+        }
+        Event::End(Tag::Image(link_type, url, title)) if !url.starts_with("mxc://") => {
+            Event::End(Tag::Link(link_type, url, title))
+        }
+        event => event,
+    });
+    let mut html = String::new();
+    html::push_html(&mut html, events);
+    html.trim_end().to_string()
+}
+
+/// Renders the given Markdown to plain text, keeping lists, links and code readable
+pub fn to_plain(md: &str) -> String {
+    let mut plain = String::new();
+    // The number of the next item of each list, None for unordered lists
+    let mut lists: Vec<Option<u64>> = vec![];
+    // Where the text of the current link starts
+    let mut link_start = 0;
+    for event in parser(md) {
+        match event {
+            Event::Text(text) | Event::Html(text) => plain.push_str(&text),
+            Event::Code(code) => {
+                plain.push('`');
+                plain.push_str(&code);
+                plain.push('`');
+            }
+            Event::SoftBreak => plain.push(' '),
+            Event::HardBreak => plain.push('\n'),
+            Event::Rule => plain.push_str("---\n\n"),
+            Event::TaskListMarker(done) => plain.push_str(if done { "[x] " } else { "[ ] " }),
+            Event::Start(Tag::List(start)) => {
+                // A nested list starts on its own line
+                if !plain.is_empty() && !plain.ends_with('\n') {
+                    plain.push('\n');
+                }
+                lists.push(start);
+            }
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    plain.push('\n');
+                }
+            }
+            Event::Start(Tag::Item) => {
+                plain.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        plain.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => plain.push_str("- "),
+                }
+            }
+            Event::End(Tag::Item) if !plain.ends_with('\n') => plain.push('\n'),
+            Event::Start(Tag::BlockQuote) => plain.push_str("> "),
+            Event::Start(Tag::CodeBlock(_)) => plain.push_str("```\n"),
+            Event::End(Tag::CodeBlock(_)) => plain.push_str("```\n\n"),
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => link_start = plain.len(),
+            // Autolinks are readable already
+            Event::End(Tag::Link(_, url, _)) | Event::End(Tag::Image(_, url, _))
+                if plain[link_start..] != *url =>
+            {
+                plain.push_str(&format!(" ({})", url));
+            }
+            Event::End(Tag::Paragraph) | Event::End(Tag::Heading(_)) => {
+                plain.push_str(if lists.is_empty() { "\n\n" } else { "\n" });
+            }
+            Event::End(Tag::TableCell) => plain.push_str(" | "),
+            Event::End(Tag::TableHead) | Event::End(Tag::TableRow) => plain.push('\n'),
+            _ => (),
+        }
+    }
+    plain.trim_end().to_string()
+}
+
+/// Whether the given Markdown contains any formatting, so that it needs a formatted body
+pub fn is_formatted(md: &str) -> bool {
+    parser(md).any(|event| {
+        !matches!(
+            event,
+            Event::Text(_)
+                | Event::SoftBreak
+                | Event::Start(Tag::Paragraph)
+                | Event::End(Tag::Paragraph)
+        )
+    })
+}
+
+/// Escapes all characters with a meaning in Markdown, so that the given text
+/// is shown as it is, when it is part of Markdown
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_{}[]()<>#+-=.!|~&".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn parser(md: &str) -> Parser<'_> {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    Parser::new_ext(md, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(to_plain("Some **bold** and `code`"), "Some bold and `code`");
+        assert_eq!(
+            to_plain("[Matrix](https://matrix.org) <https://example.org>"),
+            "Matrix (https://matrix.org) https://example.org"
+        );
+        assert_eq!(to_plain("# Title\n\nText"), "Title\n\nText");
+    }
+
+    #[test]
+    fn plain_lists() {
+        assert_eq!(
+            to_plain("* one\n* two\n  1. three\n  2. four\n\nEnd"),
+            "- one\n- two\n  1. three\n  2. four\n\nEnd"
+        );
+        assert_eq!(to_plain("3. a\n4. b"), "3. a\n4. b");
+    }
+
+    #[test]
+    fn escaped_text_is_shown_as_it_is() {
+        let text = "*not bold* [no](link) <b>no html</b> # no title\n- no list";
+        let md = escape(text);
+        assert!(!is_formatted(&md));
+        assert_eq!(to_plain(&md), text.replace('\n', " "));
+        assert_eq!(escape("a_b"), "a\\_b");
+    }
+
+    #[test]
+    fn html() {
+        assert!(!is_formatted("just text"));
+        assert!(is_formatted("**bold**"));
+        assert_eq!(
+            to_html("<script>x</script>"),
+            "&lt;script&gt;x&lt;/script&gt;"
+        );
+        assert_eq!(
+            to_html("![img](https://example.org/a.png)"),
+            "<p><a href=\"https://example.org/a.png\">img</a></p>"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Building and parsing references to users, rooms and events.
+//!
+//! Clients show links to `https://matrix.to/#/...` as "pills". To notify a user,
+//! a message should contain a pill and list the user in its `m.mentions`
+//! (see `ActiveBot::send_html_message_with_mentions()`).
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::handlers::Message;
+//! use matrix_bot_api::mentions::{self, Mentions};
+//! use matrix_bot_api::{ActiveBot, MessageType};
+//! # fn f(bot: &ActiveBot, message: &Message) {
+//! let mentions = Mentions::from_message(message);
+//! for user in mentions.users.iter().map(|u| u.as_str()) {
+//!     let html = format!("Hello {}!", mentions::user_pill(user, user));
+//!     let plain = format!("Hello {}!", user);
+//!     let msgtype = MessageType::TextMessage;
+//!     bot.send_html_message_with_mentions(&plain, &html, &message.room, msgtype, &[user]);
+//! }
+//! # }
+//! ```
+use crate::html;
+use crate::Message;
+use serde_json::value::Value as JsonValue;
+use url::percent_encoding::percent_decode;
+
+const MATRIX_TO: &str = "https://matrix.to/#/";
+
+/// A link to an event in a room
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permalink {
+    /// The room-id or alias of the room
+    pub room: String,
+    pub event_id: String,
+}
+
+/// Everything a message refers to
+#[derive(Clone, Debug, Default)]
+pub struct Mentions {
+    /// The user-ids of all pills and of the `m.mentions` of the message
+    pub users: Vec<String>,
+    /// The room-ids and aliases of all room pills
+    pub rooms: Vec<String>,
+    /// All links to events
+    pub events: Vec<Permalink>,
+    /// Whether the message mentions the whole room (by `m.mentions`)
+    pub room: bool,
+}
+
+impl Mentions {
+    /// Collects the references of the formatted body and the `m.mentions` of the given message
+    pub fn from_message(message: &Message) -> Mentions {
+        let mut mentions = match &message.formatted_body {
+            Some(html) => Mentions::from_html(html),
+            None => Mentions::default(),
+        };
+
+        let source: Option<JsonValue> = message
+            .source
+            .as_ref()
+            .and_then(|source| serde_json::from_str(source).ok());
+        if let Some(source) = source {
+            let m_mentions = &source["content"]["m.mentions"];
+            if let Some(users) = m_mentions["user_ids"].as_array() {
+                for user in users.iter().filter_map(|u| u.as_str()) {
+                    mentions.add_user(user);
+                }
+            }
+            mentions.room = m_mentions["room"].as_bool().unwrap_or(false);
+        }
+        mentions
+    }
+
+    /// Collects the references of all links in the given HTML
+    pub fn from_html(html: &str) -> Mentions {
+        let mut mentions = Mentions::default();
+        for href in links(html) {
+            let mut parts = match parse_link(&href) {
+                Some(x) => x.into_iter(),
+                None => continue,
+            };
+            match (parts.next(), parts.next()) {
+                (Some(id), None) if id.starts_with('@') => mentions.add_user(&id),
+                (Some(id), None)
+                    if (id.starts_with('#') || id.starts_with('!'))
+                        && !mentions.rooms.contains(&id) =>
+                {
+                    mentions.rooms.push(id)
+                }
+                (Some(room), Some(event_id)) if event_id.starts_with('$') => {
+                    mentions.events.push(Permalink { room, event_id })
+                }
+                _ => (),
+            }
+        }
+        mentions
+    }
+
+    fn add_user(&mut self, user_id: &str) {
+        if !self.users.iter().any(|u| u == user_id) {
+            self.users.push(user_id.to_string());
+        }
+    }
+}
+
+/// The link to the given user, room, alias or event
+pub fn link(id: &str) -> String {
+    format!("{}{}", MATRIX_TO, encode_id(id))
+}
+
+/// The link to the given event in the given room (room-id or alias)
+pub fn permalink(room: &str, event_id: &str) -> String {
+    format!("{}/{}", link(room), encode_id(event_id))
+}
+
+/// The pill of the given user, showing the given name (usually the display name)
+pub fn user_pill(user_id: &str, display_name: &str) -> String {
+    format!(
+        "<a href=\"{}\">{}</a>",
+        html::escape(&link(user_id)),
+        html::escape(display_name)
+    )
+}
+
+/// The pill of the given room (room-id or alias)
+pub fn room_pill(room: &str) -> String {
+    format!(
+        "<a href=\"{}\">{}</a>",
+        html::escape(&link(room)),
+        html::escape(room)
+    )
+}
+
+/// Encodes the characters, that would end an id in a link
+fn encode_id(id: &str) -> String {
+    id.replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('?', "%3F")
+        .replace(' ', "%20")
+        .replace('"', "%22")
+}
+
+/// The targets of all links in the given HTML
+fn links(html: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + 5..];
+        let quote = match rest.chars().next() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => continue,
+        };
+        rest = &rest[1..];
+        let end = match rest.find(quote) {
+            Some(x) => x,
+            None => break,
+        };
+        links.push(rest[..end].replace("&amp;", "&"));
+        rest = &rest[end..];
+    }
+    links
+}
+
+/// The decoded ids of a matrix.to- or matrix:-link (e.g. room and event-id)
+fn parse_link(href: &str) -> Option<Vec<String>> {
+    let path = if let Some(path) = href.strip_prefix(MATRIX_TO) {
+        path.split('?').next()?.to_string()
+    } else if let Some(path) = href.strip_prefix("matrix:") {
+        // matrix:u/user:server, matrix:r/alias:server, matrix:roomid/id:server/e/event
+        let path = path.split('?').next()?;
+        let mut ids = vec![];
+        let mut parts = path.split('/');
+        while let (Some(kind), Some(id)) = (parts.next(), parts.next()) {
+            let sigil = match kind {
+                "u" => '@',
+                "r" => '#',
+                "roomid" => '!',
+                "e" => '$',
+                _ => return None,
+            };
+            ids.push(format!("{}{}", sigil, id));
+        }
+        ids.join("/")
+    } else {
+        return None;
+    };
+
+    Some(
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                percent_decode(part.as_bytes())
+                    .decode_utf8_lossy()
+                    .to_string()
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(parts: &[&str]) -> Option<Vec<String>> {
+        Some(parts.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn matrix_to_links() {
+        assert_eq!(
+            parse_link("https://matrix.to/#/@alice:example.org"),
+            ids(&["@alice:example.org"])
+        );
+        assert_eq!(
+            parse_link("https://matrix.to/#/%23room%3Aexample.org/$event?via=example.org"),
+            ids(&["#room:example.org", "$event"])
+        );
+        assert_eq!(
+            parse_link(&permalink("!a/b:example.org", "$e")),
+            ids(&["!a/b:example.org", "$e"])
+        );
+    }
+
+    #[test]
+    fn matrix_uris() {
+        assert_eq!(
+            parse_link("matrix:u/alice:example.org?action=chat"),
+            ids(&["@alice:example.org"])
+        );
+        assert_eq!(
+            parse_link("matrix:roomid/room:example.org/e/event"),
+            ids(&["!room:example.org", "$event"])
+        );
+        assert_eq!(parse_link("matrix:x/unknown"), None);
+    }
+
+    #[test]
+    fn other_links() {
+        assert_eq!(parse_link("https://example.org/#/@alice:example.org"), None);
+        assert_eq!(parse_link("mailto:alice@example.org"), None);
+    }
+
+    #[test]
+    fn mentions_in_html() {
+        let html = format!(
+            "{} {} <a href='{}'>x</a> {}",
+            user_pill("@alice:example.org", "Alice <3"),
+            room_pill("#room:example.org"),
+            permalink("!room:example.org", "$event"),
+            user_pill("@alice:example.org", "Alice"),
+        );
+        let mentions = Mentions::from_html(&html);
+        assert_eq!(mentions.users, vec!["@alice:example.org".to_string()]);
+        assert_eq!(mentions.rooms, vec!["#room:example.org".to_string()]);
+        assert_eq!(
+            mentions.events,
+            vec![Permalink {
+                room: "!room:example.org".to_string(),
+                event_id: "$event".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+use tracing::{error, info};
+
+/// Health-metrics of one bot, exposed in the Prometheus text format
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub syncs: IntCounter,
+    pub sync_errors: IntCounter,
+    pub messages_received: IntCounterVec,
+    pub handler_invocations: IntCounterVec,
+    pub handler_latency: HistogramVec,
+    pub messages_sent: IntCounter,
+    pub messages_failed: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let syncs = IntCounter::new(
+            "matrix_bot_syncs_total",
+            "Number of successful sync-requests",
+        )
+        .unwrap();
+        let sync_errors = IntCounter::new(
+            "matrix_bot_sync_errors_total",
+            "Number of failed sync-requests",
+        )
+        .unwrap();
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "matrix_bot_messages_received_total",
+                "Number of received messages per room",
+            ),
+            &["room"],
+        )
+        .unwrap();
+        let handler_invocations = IntCounterVec::new(
+            Opts::new(
+                "matrix_bot_handler_invocations_total",
+                "Number of messages given to each handler",
+            ),
+            &["handler"],
+        )
+        .unwrap();
+        let handler_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "matrix_bot_handler_duration_seconds",
+                "Time each handler took to handle a message",
+            ),
+            &["handler"],
+        )
+        .unwrap();
+        let messages_sent = IntCounter::new(
+            "matrix_bot_messages_sent_total",
+            "Number of messages sent by the bot",
+        )
+        .unwrap();
+        let messages_failed = IntCounter::new(
+            "matrix_bot_messages_failed_total",
+            "Number of messages the bot failed to send",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(syncs.clone())).unwrap();
+        registry.register(Box::new(sync_errors.clone())).unwrap();
+        registry
+            .register(Box::new(messages_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(handler_invocations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(handler_latency.clone()))
+            .unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry
+            .register(Box::new(messages_failed.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            syncs,
+            sync_errors,
+            messages_received,
+            handler_invocations,
+            handler_latency,
+            messages_sent,
+            messages_failed,
+        }
+    }
+
+    /// Records how long the given handler took for one message
+    pub fn observe_handler(&self, handler: &str, duration: Duration) {
+        self.handler_invocations.with_label_values(&[handler]).inc();
+        self.handler_latency
+            .with_label_values(&[handler])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Starts a background-thread serving all metrics on the given address.
+    /// Every request, regardless of its path, gets the metrics as answer.
+    pub fn serve(&self, addr: &str) {
+        let server = match Server::http(addr) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(
+                    target: "matrix_bot_api::metrics",
+                    "Could not start metrics-listener on {}: {}", addr, e
+                );
+                return;
+            }
+        };
+        info!(target: "matrix_bot_api::metrics", addr = addr, "Serving metrics");
+
+        let registry = self.registry.clone();
+        thread::spawn(move || {
+            let encoder = TextEncoder::new();
+            let content_type =
+                Header::from_bytes(&b"Content-Type"[..], encoder.format_type()).unwrap();
+            for request in server.incoming_requests() {
+                let mut buffer = vec![];
+                if let Err(e) = encoder.encode(&registry.gather(), &mut buffer) {
+                    error!(target: "matrix_bot_api::metrics", "Could not encode metrics: {}", e);
+                }
+                let response = Response::from_data(buffer).with_header(content_type.clone());
+                if let Err(e) = request.respond(response) {
+                    error!(target: "matrix_bot_api::metrics", "Could not send metrics: {}", e);
+                }
+            }
+        });
+    }
+}
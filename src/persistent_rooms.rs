@@ -0,0 +1,105 @@
+use crate::{ActiveBot, MatrixBot, Room};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The delay before joining a persistent room is retried, after the first failure ...
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+/// ... doubled with each failure up to this
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// A room the bot should always be in (see `MatrixBot::add_persistent_room()`)
+pub(crate) struct PersistentRoom {
+    /// The room-id or alias, as configured
+    pub(crate) room: String,
+    pub(crate) via_servers: Vec<String>,
+    /// The room-id, while the bot is in the room
+    pub(crate) room_id: Option<String>,
+    /// Whether the room was added by the configuration file (and not in code)
+    pub(crate) from_file: bool,
+    /// When to try joining the room (again)
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+impl PersistentRoom {
+    pub(crate) fn new(room: &str, via_servers: Vec<String>) -> PersistentRoom {
+        PersistentRoom {
+            room: room.to_string(),
+            via_servers,
+            room_id: None,
+            from_file: false,
+            retry_at: Instant::now(),
+            backoff: MIN_BACKOFF,
+        }
+    }
+}
+
+impl MatrixBot {
+    /// Joins all persistent rooms the bot is not in, unless the last try to join them
+    /// failed only recently
+    pub(crate) fn join_persistent_rooms(&mut self, active_bot: &ActiveBot) {
+        let now = Instant::now();
+        for persistent in self.persistent_rooms.iter_mut() {
+            if persistent.room_id.is_some() || persistent.retry_at > now {
+                continue;
+            }
+            let via: Vec<&str> = persistent.via_servers.iter().map(|s| s.as_str()).collect();
+            match active_bot.join_room(&persistent.room, &via) {
+                Ok(room_id) => {
+                    persistent.room_id = Some(room_id);
+                    persistent.backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    warn!(
+                        target: "matrix_bot_api::rooms",
+                        room = persistent.room.as_str(),
+                        "Could not join persistent room, retrying in {:?}: {:?}",
+                        persistent.backoff,
+                        e
+                    );
+                    persistent.retry_at = now + persistent.backoff;
+                    persistent.backoff = (persistent.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Notices when the bot left a persistent room (to rejoin it with the next sync)
+    /// or was invited into it. The aliases of persistent rooms the bot is not in yet
+    /// are resolved, to recognize invites into them.
+    pub(crate) fn update_persistent_rooms(&mut self, room: &Room, active_bot: &ActiveBot) {
+        let mut persistent = self
+            .persistent_rooms
+            .iter_mut()
+            .find(|p| p.room_id.as_ref() == Some(&room.id) || p.room == room.id);
+        if persistent.is_none() && room.membership.is_invited() {
+            persistent = self
+                .persistent_rooms
+                .iter_mut()
+                .filter(|p| p.room_id.is_none() && p.room.starts_with('#'))
+                .find(|p| active_bot.resolve_alias(&p.room).ok().as_ref() == Some(&room.id));
+        }
+        let persistent = match persistent {
+            Some(x) => x,
+            None => return,
+        };
+
+        if room.membership.is_left() {
+            warn!(
+                target: "matrix_bot_api::rooms",
+                room = persistent.room.as_str(),
+                "Left persistent room, rejoining"
+            );
+            persistent.room_id = None;
+            persistent.retry_at = Instant::now();
+        } else if room.membership.is_invited() {
+            info!(
+                target: "matrix_bot_api::rooms",
+                room = persistent.room.as_str(),
+                "Invited into persistent room, rejoining"
+            );
+            persistent.room_id = Some(room.id.clone());
+            persistent.backoff = MIN_BACKOFF;
+        }
+    }
+}
@@ -0,0 +1,741 @@
+//! Handlers loaded from shared libraries at runtime.
+//!
+//! A plugin is a library (crate-type "cdylib") that implements [`Plugin`] and exports it
+//! with [`export_plugin!`]. The bot loads it with [`DynamicHandler::load()`] (or from the
+//! `plugin_libraries` of a `BotConfig`), so plugins can be added without recompiling the bot.
+//!
+//! Host and plugin only exchange JSON through a small C ABI (see [`ABI_VERSION`]), so they
+//! do not need to be built with the same compiler or version of this crate. That is why
+//! plugins use their own types ([`PluginMessage`], [`PluginResult`], message types as
+//! strings) instead of those of the `handlers` module.
+//! Loading WebAssembly-modules in a sandbox is not supported: A plugin runs inside the
+//! process of the bot and can do everything the bot can.
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::export_plugin;
+//! use matrix_bot_api::plugin::{Host, Plugin, PluginMessage, PluginResult};
+//! use serde_json::value::Value as JsonValue;
+//!
+//! struct Greeter {
+//!     greeting: String,
+//! }
+//!
+//! impl Plugin for Greeter {
+//!     fn new(config: &JsonValue) -> Greeter {
+//!         let greeting = config["greeting"].as_str().unwrap_or("Hello");
+//!         Greeter { greeting: greeting.to_string() }
+//!     }
+//!
+//!     fn handle_message(&mut self, host: &Host, message: &PluginMessage) -> PluginResult {
+//!         if message.body != "!greet" {
+//!             return PluginResult::ContinueHandling;
+//!         }
+//!         let text = format!("{} {}!", self.greeting, message.sender);
+//!         host.send_message(&message.room, &text, "m.text");
+//!         PluginResult::StopHandling
+//!     }
+//!
+//!     fn name(&self) -> &str {
+//!         "greeter"
+//!     }
+//! }
+//!
+//! export_plugin!(Greeter);
+//! ```
+//!
+//! [`Plugin`]: trait.Plugin.html
+//! [`export_plugin!`]: ../macro.export_plugin.html
+//! [`DynamicHandler::load()`]: struct.DynamicHandler.html#method.load
+//! [`ABI_VERSION`]: constant.ABI_VERSION.html
+//! [`PluginMessage`]: struct.PluginMessage.html
+//! [`PluginResult`]: enum.PluginResult.html
+use crate::handlers::{HandleResult, Message, MessageHandler};
+use crate::{ActiveBot, MessageType, PollResponse};
+use libloading::Library;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use tracing::{error, info, warn};
+
+/// The version of the plugin ABI. Plugins built for another version are not loaded.
+///
+/// A plugin exports these functions (see `export_plugin!`), all strings are
+/// NUL-terminated UTF-8 JSON:
+/// * `u32 matrix_bot_plugin_abi_version()`
+/// * `void *matrix_bot_plugin_new(const char *config)`: Creates the plugin, NULL on failure
+/// * `const char *matrix_bot_plugin_name(void *plugin)`: Valid until the plugin is freed
+/// * `i32 matrix_bot_plugin_event(void *plugin, const PluginHost *host, const char *event)`:
+///   Handles an event of the bot, `{"event": "<type>", ...}` with the types
+///   * `init`, `config_changed`, `shutdown`: See `MessageHandler`, returns 0
+///   * `message` with the `message`: Returns 1 to stop handling the message, 0 to continue
+///   * `expects_message` with the `message`: Returns 1 if the plugin waits for the message
+///   * `poll_response` with the `response`: Returns 0
+/// * `void matrix_bot_plugin_free(void *plugin)`
+///
+/// The plugin calls the bot through the `PluginHost` (see `Host` for the requests).
+pub const ABI_VERSION: u32 = 2;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type NewFn = unsafe extern "C" fn(*const c_char) -> *mut c_void;
+type NameFn = unsafe extern "C" fn(*mut c_void) -> *const c_char;
+type EventFn = unsafe extern "C" fn(*mut c_void, *const PluginHost, *const c_char) -> i32;
+type FreeFn = unsafe extern "C" fn(*mut c_void);
+
+/// The functions of the bot a plugin can call, given to it with each message
+#[repr(C)]
+pub struct PluginHost {
+    ctx: *const c_void,
+    /// Executes a JSON request and returns the JSON response, to be freed with `free`
+    call: extern "C" fn(*const c_void, *const c_char) -> *mut c_char,
+    free: extern "C" fn(*mut c_char),
+}
+
+/// Why a plugin could not be loaded
+#[derive(Debug)]
+pub enum PluginError {
+    /// The library or one of its functions could not be loaded
+    Library(String),
+    /// The plugin was built for another ABI version
+    AbiVersion(u32),
+    /// The plugin could not be created (e.g. because of its configuration)
+    Init,
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginError::Library(e) => write!(f, "Could not load plugin: {}", e),
+            PluginError::AbiVersion(v) => write!(
+                f,
+                "Plugin was built for ABI version {}, not {}",
+                v, ABI_VERSION
+            ),
+            PluginError::Init => write!(f, "Plugin could not be created"),
+        }
+    }
+}
+
+/// What to do after a plugin handled a message (see `HandleResult`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum PluginResult {
+    /// Give this message to the next handler as well
+    ContinueHandling = 0,
+    /// Stop handling this message
+    StopHandling = 1,
+}
+
+/// A message, as it is given to plugins
+#[derive(Clone, Debug)]
+pub struct PluginMessage {
+    pub room: String,
+    pub sender: String,
+    pub event_id: String,
+    /// "m.text", "m.notice", ...
+    pub msgtype: String,
+    pub body: String,
+    pub formatted_body: Option<String>,
+}
+
+impl PluginMessage {
+    fn from_message(message: &Message) -> PluginMessage {
+        PluginMessage {
+            room: message.room.clone(),
+            sender: message.sender.clone(),
+            event_id: message.id.clone(),
+            msgtype: message.mtype.clone(),
+            body: message.body.clone(),
+            formatted_body: message.formatted_body.clone(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json!({
+            "room": self.room,
+            "sender": self.sender,
+            "event_id": self.event_id,
+            "msgtype": self.msgtype,
+            "body": self.body,
+            "formatted_body": self.formatted_body,
+        })
+    }
+
+    fn from_json(js: &JsonValue) -> PluginMessage {
+        let field = |key: &str| js[key].as_str().unwrap_or_default().to_string();
+        PluginMessage {
+            room: field("room"),
+            sender: field("sender"),
+            event_id: field("event_id"),
+            msgtype: field("msgtype"),
+            body: field("body"),
+            formatted_body: js["formatted_body"].as_str().map(String::from),
+        }
+    }
+}
+
+fn poll_response_to_json(response: &PollResponse) -> JsonValue {
+    json!({
+        "room": response.room,
+        "poll_id": response.poll_id,
+        "event_id": response.event_id,
+        "sender": response.sender,
+        "answers": response.answers,
+    })
+}
+
+fn poll_response_from_json(js: &JsonValue) -> PollResponse {
+    let field = |key: &str| js[key].as_str().unwrap_or_default().to_string();
+    let answers = js["answers"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    PollResponse {
+        room: field("room"),
+        poll_id: field("poll_id"),
+        event_id: field("event_id"),
+        sender: field("sender"),
+        answers: answers
+            .iter()
+            .filter_map(|answer| answer.as_str().map(String::from))
+            .collect(),
+    }
+}
+
+/* --------- Host side ------------ */
+
+/// A handler loaded from a plugin library
+pub struct DynamicHandler {
+    name: String,
+    plugin: *mut c_void,
+    event: EventFn,
+    free: FreeFn,
+    // Has to outlive the plugin, so it is dropped last
+    _library: Library,
+}
+
+// SAFETY: The raw pointer is the only thing keeping DynamicHandler from being Send.
+// It points to the state of the plugin, which is owned by this handler alone: It is
+// created in `load()`, freed in `drop()` and never handed out. All calls into the plugin
+// take `&mut self` or `&self` of the handler, so they are serialized like those of any
+// other handler, and the plugin is never accessed from two threads at the same time.
+// Moving it to another thread is sound, because `export_plugin!` only accepts plugins
+// that are `Send` (see `Plugin`). The function pointers and the `Library` are `Send`.
+unsafe impl Send for DynamicHandler {}
+
+impl DynamicHandler {
+    /// Loads the plugin from the given library and creates it with the given configuration
+    pub fn load(path: &Path, config: &JsonValue) -> Result<DynamicHandler, PluginError> {
+        let library = Library::new(path).map_err(|e| PluginError::Library(e.to_string()))?;
+        unsafe {
+            let abi_version: AbiVersionFn = symbol(&library, b"matrix_bot_plugin_abi_version\0")?;
+            let version = abi_version();
+            if version != ABI_VERSION {
+                return Err(PluginError::AbiVersion(version));
+            }
+            let new: NewFn = symbol(&library, b"matrix_bot_plugin_new\0")?;
+            let name: NameFn = symbol(&library, b"matrix_bot_plugin_name\0")?;
+            let event: EventFn = symbol(&library, b"matrix_bot_plugin_event\0")?;
+            let free: FreeFn = symbol(&library, b"matrix_bot_plugin_free\0")?;
+
+            let config = to_c_string(config);
+            let plugin = new(config.as_ptr());
+            if plugin.is_null() {
+                return Err(PluginError::Init);
+            }
+            let name_ptr = name(plugin);
+            if name_ptr.is_null() {
+                free(plugin);
+                return Err(PluginError::Init);
+            }
+            let name = CStr::from_ptr(name_ptr).to_string_lossy().to_string();
+            info!(
+                target: "matrix_bot_api::handler",
+                name = name.as_str(),
+                path = %path.display(),
+                "Loaded plugin"
+            );
+            Ok(DynamicHandler {
+                name,
+                plugin,
+                event,
+                free,
+                _library: library,
+            })
+        }
+    }
+
+    /// Gives the event to the plugin and returns its answer.
+    /// Without a bot (in `expects_message()`), the plugin can not call it.
+    fn send_event(&self, bot: Option<&ActiveBot>, event: &JsonValue) -> i32 {
+        let host = PluginHost {
+            ctx: bot.map_or(std::ptr::null(), |bot| {
+                bot as *const ActiveBot as *const c_void
+            }),
+            call: host_call,
+            free: host_free,
+        };
+        let event = to_c_string(event);
+        // SAFETY: The plugin is valid until `drop()`, host and event outlive the call
+        unsafe { (self.event)(self.plugin, &host, event.as_ptr()) }
+    }
+}
+
+impl MessageHandler for DynamicHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let message = PluginMessage::from_message(message).to_json();
+        let event = json!({ "event": "message", "message": message });
+        match self.send_event(Some(bot), &event) {
+            1 => HandleResult::StopHandling,
+            _ => HandleResult::ContinueHandling,
+        }
+    }
+
+    fn expects_message(&self, message: &Message) -> bool {
+        let message = PluginMessage::from_message(message).to_json();
+        let event = json!({ "event": "expects_message", "message": message });
+        self.send_event(None, &event) == 1
+    }
+
+    fn init_handler(&mut self, bot: &ActiveBot) {
+        self.send_event(Some(bot), &json!({ "event": "init" }));
+    }
+
+    fn handle_poll_response(&mut self, bot: &ActiveBot, response: &PollResponse) {
+        let event =
+            json!({ "event": "poll_response", "response": poll_response_to_json(response) });
+        self.send_event(Some(bot), &event);
+    }
+
+    fn config_changed(&mut self, bot: &ActiveBot) {
+        self.send_event(Some(bot), &json!({ "event": "config_changed" }));
+    }
+
+    fn shutdown_handler(&mut self, bot: &ActiveBot) {
+        self.send_event(Some(bot), &json!({ "event": "shutdown" }));
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for DynamicHandler {
+    fn drop(&mut self) {
+        // SAFETY: The plugin was created by the library, which is still loaded
+        unsafe { (self.free)(self.plugin) };
+    }
+}
+
+unsafe fn symbol<T: Copy>(library: &Library, name: &[u8]) -> Result<T, PluginError> {
+    library
+        .get::<T>(name)
+        .map(|symbol| *symbol)
+        .map_err(|e| PluginError::Library(e.to_string()))
+}
+
+/// Executes a request of a plugin. Panics must not unwind into the plugin.
+extern "C" fn host_call(ctx: *const c_void, request: *const c_char) -> *mut c_char {
+    if ctx.is_null() {
+        return to_c_string(&json!({ "error": "The bot can not be called here" })).into_raw();
+    }
+    if request.is_null() {
+        return to_c_string(&json!({ "error": "Missing request" })).into_raw();
+    }
+    let response = panic::catch_unwind(AssertUnwindSafe(|| {
+        // SAFETY: ctx is the ActiveBot given to `send_event()`, which outlives the call
+        let bot = unsafe { &*(ctx as *const ActiveBot) };
+        // SAFETY: The plugin passes a NUL-terminated string, that outlives the call
+        let request = unsafe { CStr::from_ptr(request) };
+        match serde_json::from_slice(request.to_bytes()) {
+            Ok(request) => execute(bot, &request),
+            Err(e) => json!({ "error": format!("Invalid request: {}", e) }),
+        }
+    }))
+    .unwrap_or_else(|_| json!({ "error": "The bot panicked" }));
+    to_c_string(&response).into_raw()
+}
+
+extern "C" fn host_free(response: *mut c_char) {
+    if !response.is_null() {
+        drop(unsafe { CString::from_raw(response) });
+    }
+}
+
+fn execute(bot: &ActiveBot, request: &JsonValue) -> JsonValue {
+    let arg = |key: &str| request[key].as_str().unwrap_or_default();
+    let msgtype = match arg("msgtype") {
+        "m.notice" => MessageType::RoomNotice,
+        _ => MessageType::TextMessage,
+    };
+    match arg("action") {
+        "send_message" => match request["html"].as_str() {
+            Some(html) => bot.send_sanitized_html_message(arg("body"), html, arg("room"), msgtype),
+            None => bot.send_message(arg("body"), arg("room"), msgtype),
+        },
+        "send_markdown" => bot.send_markdown(arg("body"), arg("room"), msgtype),
+        "leave_room" => bot.leave_room(arg("room")),
+        "room_setting" => {
+            let setting = bot.room_setting(arg("room"), arg("key"));
+            return json!({ "value": setting });
+        }
+        "is_bot_admin" => return json!({ "value": bot.is_bot_admin(arg("user")) }),
+        action => {
+            warn!(target: "matrix_bot_api::handler", action = action, "Unknown plugin request");
+            return json!({ "error": format!("Unknown action \"{}\"", action) });
+        }
+    }
+    json!({})
+}
+
+fn to_c_string(js: &JsonValue) -> CString {
+    // JSON never contains a NUL-byte, it is escaped
+    CString::new(js.to_string()).unwrap_or_default()
+}
+
+/* --------- Plugin side ------------ */
+
+/// A handler in a plugin library, exported with `export_plugin!`.
+/// Like the handlers of the bot, a plugin may be moved to another thread, so it has to be `Send`.
+pub trait Plugin: Send {
+    /// Creates the plugin with its configuration (the `config` of the plugin library
+    /// in the configuration file of the bot)
+    fn new(config: &JsonValue) -> Self
+    where
+        Self: Sized;
+
+    /// Will be called for every text message send to a room the bot is in
+    fn handle_message(&mut self, host: &Host, message: &PluginMessage) -> PluginResult;
+
+    /// Whether this plugin waits for the given message (see `MessageHandler::expects_message()`)
+    fn expects_message(&self, _message: &PluginMessage) -> bool {
+        false
+    }
+
+    /// Will be called once the bot has started
+    fn init(&mut self, _host: &Host) {}
+
+    /// Will be called for every vote in a poll in the rooms of the bot
+    fn handle_poll_response(&mut self, _host: &Host, _response: &PollResponse) {}
+
+    /// Will be called after the configuration of the bot was reloaded
+    fn config_changed(&mut self, _host: &Host) {}
+
+    /// Will be called once the bot is shutting down
+    fn shutdown(&mut self, _host: &Host) {}
+
+    /// Name of the plugin, used in logs and to enable or disable it per room
+    fn name(&self) -> &str;
+}
+
+/// The bot, as seen by a plugin.
+/// Message types are given as their names, e.g. "m.text" or "m.notice".
+pub struct Host {
+    host: *const PluginHost,
+}
+
+impl Host {
+    /// Sends a message to the given room
+    pub fn send_message(&self, room: &str, body: &str, msgtype: &str) {
+        self.call(&json!({
+            "action": "send_message",
+            "room": room,
+            "body": body,
+            "msgtype": msgtype,
+        }));
+    }
+
+    /// Sends an HTML message to the given room. The HTML is sanitized
+    /// (see `ActiveBot::send_sanitized_html_message()`).
+    pub fn send_html_message(&self, room: &str, body: &str, html: &str, msgtype: &str) {
+        self.call(&json!({
+            "action": "send_message",
+            "room": room,
+            "body": body,
+            "html": html,
+            "msgtype": msgtype,
+        }));
+    }
+
+    /// Sends a Markdown message to the given room (see `ActiveBot::send_markdown()`)
+    pub fn send_markdown(&self, room: &str, markdown: &str, msgtype: &str) {
+        self.call(&json!({
+            "action": "send_markdown",
+            "room": room,
+            "body": markdown,
+            "msgtype": msgtype,
+        }));
+    }
+
+    /// Leaves the given room
+    pub fn leave_room(&self, room: &str) {
+        self.call(&json!({ "action": "leave_room", "room": room }));
+    }
+
+    /// The setting with the given key in the given room (see `ActiveBot::room_setting()`)
+    pub fn room_setting(&self, room: &str, key: &str) -> Option<JsonValue> {
+        let response = self.call(&json!({ "action": "room_setting", "room": room, "key": key }));
+        Some(response["value"].clone()).filter(|value| !value.is_null())
+    }
+
+    /// Whether the given user is an admin of the bot (see `ActiveBot::is_bot_admin()`)
+    pub fn is_bot_admin(&self, user_id: &str) -> bool {
+        let response = self.call(&json!({ "action": "is_bot_admin", "user": user_id }));
+        response["value"].as_bool().unwrap_or(false)
+    }
+
+    /// Sends a raw request to the bot and returns its response.
+    /// While asked for `Plugin::expects_message()`, the bot can not be called.
+    pub fn call(&self, request: &JsonValue) -> JsonValue {
+        let request = to_c_string(request);
+        // SAFETY: The PluginHost is given by the bot for the duration of the event,
+        // the response is freed by the bot that allocated it
+        unsafe {
+            let host = &*self.host;
+            let response = (host.call)(host.ctx, request.as_ptr());
+            if response.is_null() {
+                return JsonValue::Null;
+            }
+            let js = serde_json::from_slice(CStr::from_ptr(response).to_bytes());
+            (host.free)(response);
+            js.unwrap_or(JsonValue::Null)
+        }
+    }
+}
+
+/// A plugin with its name, that has to stay valid for the host
+#[doc(hidden)]
+pub struct PluginState<P> {
+    plugin: P,
+    name: CString,
+}
+
+#[doc(hidden)]
+pub unsafe fn new_plugin<P: Plugin>(config: *const c_char) -> *mut c_void {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let config = CStr::from_ptr(config);
+        let config = serde_json::from_slice(config.to_bytes()).unwrap_or(JsonValue::Null);
+        let plugin = P::new(&config);
+        let name = CString::new(plugin.name()).unwrap_or_default();
+        Box::into_raw(Box::new(PluginState { plugin, name })) as *mut c_void
+    }));
+    match result {
+        Ok(state) => state,
+        Err(_) => {
+            error!(target: "matrix_bot_api::handler", "Plugin panicked while being created");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[doc(hidden)]
+pub unsafe fn plugin_name<P: Plugin>(state: *mut c_void) -> *const c_char {
+    if state.is_null() {
+        return std::ptr::null();
+    }
+    let state = &*(state as *mut PluginState<P>);
+    state.name.as_ptr()
+}
+
+#[doc(hidden)]
+pub unsafe fn plugin_event<P: Plugin>(
+    state: *mut c_void,
+    host: *const PluginHost,
+    event: *const c_char,
+) -> i32 {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let plugin = &mut (*(state as *mut PluginState<P>)).plugin;
+        let event = CStr::from_ptr(event);
+        let event: JsonValue = serde_json::from_slice(event.to_bytes()).unwrap_or_default();
+        let host = Host { host };
+        match event["event"].as_str().unwrap_or_default() {
+            "message" => {
+                let message = PluginMessage::from_json(&event["message"]);
+                plugin.handle_message(&host, &message) as i32
+            }
+            "expects_message" => {
+                let message = PluginMessage::from_json(&event["message"]);
+                plugin.expects_message(&message) as i32
+            }
+            "init" => {
+                plugin.init(&host);
+                0
+            }
+            "poll_response" => {
+                let response = poll_response_from_json(&event["response"]);
+                plugin.handle_poll_response(&host, &response);
+                0
+            }
+            "config_changed" => {
+                plugin.config_changed(&host);
+                0
+            }
+            "shutdown" => {
+                plugin.shutdown(&host);
+                0
+            }
+            // Events of newer bots are ignored
+            _ => 0,
+        }
+    }));
+    result.unwrap_or_else(|_| {
+        error!(target: "matrix_bot_api::handler", "Plugin panicked");
+        0
+    })
+}
+
+#[doc(hidden)]
+pub unsafe fn free_plugin<P: Plugin>(state: *mut c_void) {
+    drop(Box::from_raw(state as *mut PluginState<P>));
+}
+
+/// Exports the given type, which implements `plugin::Plugin`, as the plugin of this library.
+/// The library has to be built with `crate-type = ["cdylib"]`.
+#[macro_export]
+macro_rules! export_plugin {
+    ($plugin:ty) => {
+        #[no_mangle]
+        pub extern "C" fn matrix_bot_plugin_abi_version() -> u32 {
+            $crate::plugin::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn matrix_bot_plugin_new(
+            config: *const std::os::raw::c_char,
+        ) -> *mut std::os::raw::c_void {
+            $crate::plugin::new_plugin::<$plugin>(config)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn matrix_bot_plugin_name(
+            plugin: *mut std::os::raw::c_void,
+        ) -> *const std::os::raw::c_char {
+            $crate::plugin::plugin_name::<$plugin>(plugin)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn matrix_bot_plugin_event(
+            plugin: *mut std::os::raw::c_void,
+            host: *const $crate::plugin::PluginHost,
+            event: *const std::os::raw::c_char,
+        ) -> i32 {
+            $crate::plugin::plugin_event::<$plugin>(plugin, host, event)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn matrix_bot_plugin_free(plugin: *mut std::os::raw::c_void) {
+            $crate::plugin::free_plugin::<$plugin>(plugin)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::StatelessHandler;
+
+    struct Counter {
+        messages: usize,
+        votes: Vec<String>,
+    }
+
+    impl Plugin for Counter {
+        fn new(_config: &JsonValue) -> Counter {
+            Counter {
+                messages: 0,
+                votes: Vec::new(),
+            }
+        }
+
+        fn handle_message(&mut self, _host: &Host, message: &PluginMessage) -> PluginResult {
+            self.messages += 1;
+            match message.body.as_str() {
+                "!count" => PluginResult::StopHandling,
+                _ => PluginResult::ContinueHandling,
+            }
+        }
+
+        fn expects_message(&self, message: &PluginMessage) -> bool {
+            message.sender == "@alice:example.org"
+        }
+
+        fn handle_poll_response(&mut self, _host: &Host, response: &PollResponse) {
+            self.votes.extend(response.answers.iter().cloned());
+        }
+
+        fn name(&self) -> &str {
+            "counter"
+        }
+    }
+
+    fn send(state: *mut c_void, event: JsonValue) -> i32 {
+        let event = to_c_string(&event);
+        unsafe { plugin_event::<Counter>(state, std::ptr::null(), event.as_ptr()) }
+    }
+
+    #[test]
+    fn events() {
+        let config = to_c_string(&JsonValue::Null);
+        let state = unsafe { new_plugin::<Counter>(config.as_ptr()) };
+        let name = unsafe { CStr::from_ptr(plugin_name::<Counter>(state)) };
+        assert_eq!(name.to_str(), Ok("counter"));
+
+        let event = |event: &str, sender: &str, body: &str| {
+            let message = json!({ "room": "!room:example.org", "sender": sender, "body": body });
+            json!({ "event": event, "message": message })
+        };
+        assert_eq!(
+            send(state, event("message", "@bob:example.org", "!count")),
+            1
+        );
+        assert_eq!(send(state, event("message", "@bob:example.org", "hi")), 0);
+        assert_eq!(
+            send(state, event("expects_message", "@bob:example.org", "hi")),
+            0
+        );
+        assert_eq!(
+            send(state, event("expects_message", "@alice:example.org", "hi")),
+            1
+        );
+
+        let response = PollResponse {
+            room: "!room:example.org".to_string(),
+            poll_id: "$poll".to_string(),
+            event_id: "$vote".to_string(),
+            sender: "@bob:example.org".to_string(),
+            answers: vec!["yes".to_string()],
+        };
+        let response =
+            json!({ "event": "poll_response", "response": poll_response_to_json(&response) });
+        send(state, response);
+        assert_eq!(send(state, json!({ "event": "unknown" })), 0);
+
+        let plugin = unsafe { &(*(state as *mut PluginState<Counter>)).plugin };
+        assert_eq!(plugin.messages, 2);
+        assert_eq!(plugin.votes, vec!["yes".to_string()]);
+        unsafe { free_plugin::<Counter>(state) };
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let bot = crate::MatrixBot::new(StatelessHandler::new()).get_activebot_clone();
+        let ctx = &bot as *const ActiveBot as *const c_void;
+        let call = |ctx: *const c_void, request: *const c_char| {
+            let response = host_call(ctx, request);
+            let js: JsonValue =
+                serde_json::from_slice(unsafe { CStr::from_ptr(response) }.to_bytes()).unwrap();
+            host_free(response);
+            js
+        };
+        assert_eq!(call(ctx, std::ptr::null())["error"], "Missing request");
+        let request = CString::new("no json").unwrap();
+        assert!(call(ctx, request.as_ptr())["error"].is_string());
+        let request = to_c_string(&json!({ "action": "is_bot_admin", "user": "@a:x" }));
+        assert!(call(std::ptr::null(), request.as_ptr())["error"].is_string());
+        assert_eq!(call(ctx, request.as_ptr())["value"], false);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{ActiveBot, Error, MatrixBot, ShutdownState};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use tracing::{debug_span, info};
+
+/// The room account data listing the polls the bot started and did not end yet
+const ACCOUNT_DATA_TYPE: &str = "matrix_bot_api.polls";
+
+/// Clients implemented polls as MSC3381 before they became part of the spec and most of
+/// them only show polls of these event types
+const START_TYPE: &str = "org.matrix.msc3381.poll.start";
+const END_TYPE: &str = "org.matrix.msc3381.poll.end";
+/// Responses are read in both formats
+const RESPONSE_TYPES: &[&str] = &["m.poll.response", "org.matrix.msc3381.poll.response"];
+/// The text of an event, as understood by clients without support for polls
+const TEXT_KEY: &str = "org.matrix.msc1767.text";
+
+/// Who can see the results of a poll before it ended
+pub enum PollKind {
+    /// Everyone can see the results at any time
+    Disclosed,
+    /// The results are only shown once the poll ended
+    Undisclosed,
+}
+
+/// A vote of a user in a poll
+#[derive(Clone, Debug)]
+pub struct PollResponse {
+    pub room: String,
+    /// The event-id of the poll (as returned by `ActiveBot::start_poll()`)
+    pub poll_id: String,
+    /// The event-id of the response itself
+    pub event_id: String,
+    pub sender: String,
+    /// The ids of the selected answers. Empty, if the user took back the vote.
+    pub answers: Vec<String>,
+}
+
+impl PollResponse {
+    /// The response in the given event of a room, if it is one
+    /// (m.poll.response or org.matrix.msc3381.poll.response)
+    pub(crate) fn from_event(room_id: &str, event: &JsonValue) -> Option<PollResponse> {
+        let event_type = event["type"].as_str()?;
+        if !RESPONSE_TYPES.contains(&event_type) {
+            return None;
+        }
+        let content = &event["content"];
+        let poll_id = content["m.relates_to"]["event_id"].as_str()?;
+        let selections = match content["m.selections"].as_array() {
+            Some(x) => x,
+            None => content["org.matrix.msc3381.poll.response"]["answers"].as_array()?,
+        };
+        Some(PollResponse {
+            room: room_id.to_string(),
+            poll_id: poll_id.to_string(),
+            event_id: event["event_id"].as_str().unwrap_or_default().to_string(),
+            sender: event["sender"].as_str()?.to_string(),
+            answers: selections
+                .iter()
+                .filter_map(|a| a.as_str().map(String::from))
+                .collect(),
+        })
+    }
+}
+
+/// One answer of a poll and how many users voted for it
+#[derive(Clone, Debug)]
+pub struct PollAnswer {
+    pub id: String,
+    pub text: String,
+    pub votes: usize,
+}
+
+/// The tallied responses of a poll
+#[derive(Clone, Debug)]
+pub struct PollResults {
+    pub question: String,
+    pub answers: Vec<PollAnswer>,
+    /// The valid selections of each user who voted
+    pub votes: HashMap<String, Vec<String>>,
+}
+
+impl PollResults {
+    /// The answers with the most votes (more than one on a tie, none without votes)
+    pub fn winners(&self) -> Vec<&PollAnswer> {
+        let max = self.answers.iter().map(|a| a.votes).max().unwrap_or(0);
+        self.answers
+            .iter()
+            .filter(|a| max > 0 && a.votes == max)
+            .collect()
+    }
+
+    /// The results as text, as used by `ActiveBot::end_poll()`
+    pub fn to_text(&self) -> String {
+        let mut text = format!("The poll has ended: {}", self.question);
+        for answer in &self.answers {
+            let unit = if answer.votes == 1 { "vote" } else { "votes" };
+            text.push_str(&format!("\n{}: {} {}", answer.text, answer.votes, unit));
+        }
+        text
+    }
+}
+
+/// Polls (org.matrix.msc3381.poll.start, m.poll.response and org.matrix.msc3381.poll.end).
+/// All these calls are blocking.
+impl ActiveBot {
+    /// Starts a poll in the given room, where each user can select one of the given
+    /// answers, and returns the event-id of the poll.
+    /// The poll is listed by `open_polls()` until it is ended with `end_poll()`.
+    pub fn start_poll(
+        &self,
+        room_id: &str,
+        question: &str,
+        answers: &[&str],
+        kind: PollKind,
+    ) -> Result<String, Error> {
+        let kind = match kind {
+            PollKind::Disclosed => "org.matrix.msc3381.poll.disclosed",
+            PollKind::Undisclosed => "org.matrix.msc3381.poll.undisclosed",
+        };
+        let mut fallback = question.to_string();
+        let answers: Vec<JsonValue> = answers
+            .iter()
+            .enumerate()
+            .map(|(i, answer)| {
+                fallback.push_str(&format!("\n{}. {}", i + 1, answer));
+                json!({ "id": (i + 1).to_string(), TEXT_KEY: answer })
+            })
+            .collect();
+        let content = json!({
+            START_TYPE: {
+                "kind": kind,
+                "max_selections": 1,
+                "question": { TEXT_KEY: question },
+                "answers": answers,
+            },
+            // For clients without support for polls
+            TEXT_KEY: fallback,
+        });
+
+        let poll_id = self.send_event(room_id, START_TYPE, &content)?;
+        info!(
+            target: "matrix_bot_api::rooms",
+            room = room_id,
+            poll = poll_id.as_str(),
+            "Started poll"
+        );
+        let mut polls = self.open_polls(room_id)?;
+        polls.push(poll_id.clone());
+        self.set_open_polls(room_id, &polls)?;
+        Ok(poll_id)
+    }
+
+    /// The event-ids of the polls the bot started in the given room and did not end yet.
+    /// They are stored on the homeserver, so they survive a restart of the bot.
+    pub fn open_polls(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let js = match self.api("get", &self.polls_path(room_id), &JsonValue::Null) {
+            Ok(js) => js,
+            Err(Error::MatrixError(ref js)) if js["errcode"] == "M_NOT_FOUND" => json!({}),
+            Err(e) => return Err(e),
+        };
+        Ok(js["polls"]
+            .as_array()
+            .map(|polls| {
+                polls
+                    .iter()
+                    .filter_map(|p| p.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Tallies all responses to the given poll so far.
+    /// Only the latest response of each user counts and invalid answers are ignored.
+    /// Fails in encrypted rooms, as the bot can not decrypt the poll and its responses
+    /// (see `ActiveBot::is_encrypted()`).
+    pub fn poll_results(&self, room_id: &str, poll_id: &str) -> Result<PollResults, Error> {
+        let path = format!(
+            "rooms/{}/event/{}",
+            encode_uid(room_id),
+            encode_uid(poll_id)
+        );
+        let start = self.api("get", &path, &JsonValue::Null)?;
+        if start["type"] == "m.room.encrypted" || self.is_encrypted(room_id)? {
+            return Err(Error::MatrixError(json!({
+                "errcode": "M_UNKNOWN",
+                "error": "Polls in encrypted rooms can not be tallied",
+            })));
+        }
+        let responses = self.poll_responses(room_id, poll_id)?;
+        Ok(tally(&start["content"], responses))
+    }
+
+    /// Ends the given poll, posts its results and returns them.
+    /// Like `poll_results()`, this fails in encrypted rooms.
+    pub fn end_poll(&self, room_id: &str, poll_id: &str) -> Result<PollResults, Error> {
+        let results = self.poll_results(room_id, poll_id)?;
+        let content = json!({
+            "m.relates_to": { "rel_type": "m.reference", "event_id": poll_id },
+            END_TYPE: {},
+            TEXT_KEY: results.to_text(),
+        });
+        self.send_event(room_id, END_TYPE, &content)?;
+        info!(target: "matrix_bot_api::rooms", room = room_id, poll = poll_id, "Ended poll");
+        let mut polls = self.open_polls(room_id)?;
+        polls.retain(|id| id != poll_id);
+        self.set_open_polls(room_id, &polls)?;
+        Ok(results)
+    }
+
+    fn set_open_polls(&self, room_id: &str, polls: &[String]) -> Result<(), Error> {
+        self.api("put", &self.polls_path(room_id), &json!({ "polls": polls }))?;
+        Ok(())
+    }
+
+    fn polls_path(&self, room_id: &str) -> String {
+        format!(
+            "user/{}/rooms/{}/account_data/{}",
+            encode_uid(&self.user_id()),
+            encode_uid(room_id),
+            ACCOUNT_DATA_TYPE
+        )
+    }
+
+    /// All responses to the given poll, with their timestamp
+    fn poll_responses(
+        &self,
+        room_id: &str,
+        poll_id: &str,
+    ) -> Result<Vec<(PollResponse, i64)>, Error> {
+        let path = format!(
+            "rooms/{}/relations/{}/m.reference",
+            encode_uid(room_id),
+            encode_uid(poll_id)
+        );
+        let mut responses = vec![];
+        let mut from: Option<String> = None;
+        loop {
+            let mut params = vec![("limit", "100".to_string())];
+            if let Some(from) = &from {
+                params.push(("from", from.clone()));
+            }
+            let js = self.versioned_api("get", "v1", &path, &params, &JsonValue::Null)?;
+            let events = js["chunk"].as_array().cloned().unwrap_or_default();
+            for event in &events {
+                if let Some(response) = PollResponse::from_event(room_id, event) {
+                    responses.push((response, event["origin_server_ts"].as_i64().unwrap_or(0)));
+                }
+            }
+
+            match js["next_batch"].as_str() {
+                Some(next) if !events.is_empty() => from = Some(next.to_string()),
+                _ => break,
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// Tallies the responses (with their timestamp) to the poll with the given start-content.
+/// Both the format of MSC3381 and the one of the spec are understood.
+fn tally(start: &JsonValue, responses: Vec<(PollResponse, i64)>) -> PollResults {
+    let stable = start["m.poll"].is_object();
+    let (poll, id_key) = if stable {
+        (&start["m.poll"], "m.id")
+    } else {
+        (&start[START_TYPE], "id")
+    };
+    let max_selections = poll["max_selections"].as_u64().unwrap_or(1) as usize;
+
+    let mut answers: Vec<PollAnswer> = poll["answers"]
+        .as_array()
+        .map(|answers| {
+            answers
+                .iter()
+                .map(|answer| PollAnswer {
+                    id: answer[id_key].as_str().unwrap_or_default().to_string(),
+                    text: text_of(answer),
+                    votes: 0,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // The latest response of each user
+    let mut latest: HashMap<String, (i64, Vec<String>)> = HashMap::new();
+    for (response, ts) in responses {
+        match latest.get(&response.sender) {
+            Some((latest_ts, _)) if *latest_ts > ts => (),
+            _ => {
+                latest.insert(response.sender, (ts, response.answers));
+            }
+        }
+    }
+
+    let mut votes = HashMap::new();
+    for (user, (_, selections)) in latest {
+        let valid: Vec<String> = selections
+            .into_iter()
+            .filter(|id| answers.iter().any(|a| a.id == *id))
+            .take(max_selections)
+            .collect();
+        for id in &valid {
+            if let Some(answer) = answers.iter_mut().find(|a| a.id == *id) {
+                answer.votes += 1;
+            }
+        }
+        if !valid.is_empty() {
+            votes.insert(user, valid);
+        }
+    }
+
+    PollResults {
+        question: text_of(&poll["question"]),
+        answers,
+        votes,
+    }
+}
+
+/// The text of an extensible event content (like the question of a poll)
+fn text_of(content: &JsonValue) -> String {
+    content["m.text"][0]["body"]
+        .as_str()
+        .or_else(|| content[TEXT_KEY].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl MatrixBot {
+    /// Gives the responses to polls (of the bot and of others) to the handlers
+    pub(crate) fn handle_poll_responses(
+        &mut self,
+        responses: Vec<PollResponse>,
+        active_bot: &ActiveBot,
+    ) {
+        // No new responses for the handlers, once the shutdown has begun
+        match self.shutdown_state {
+            ShutdownState::Running => (),
+            _ => return,
+        }
+
+        let uid = self.uid.clone().unwrap_or_default();
+        for response in responses.iter().filter(|r| r.sender != uid) {
+            let config = active_bot.room_config(&response.room).ok();
+            for handler in self.handlers.iter_mut() {
+                if let Some(config) = &config {
+                    if !config.is_enabled(handler.name()) {
+                        continue;
+                    }
+                }
+                let span = debug_span!(
+                    target: "matrix_bot_api::handler",
+                    "handler",
+                    name = handler.name()
+                );
+                let _enter = span.enter();
+                handler.handle_poll_response(active_bot, response);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(sender: &str, answers: &[&str], ts: i64) -> (PollResponse, i64) {
+        let response = PollResponse {
+            room: "!room:example.org".to_string(),
+            poll_id: "$poll".to_string(),
+            event_id: format!("${}", ts),
+            sender: sender.to_string(),
+            answers: answers.iter().map(|a| a.to_string()).collect(),
+        };
+        (response, ts)
+    }
+
+    fn start() -> JsonValue {
+        json!({
+            START_TYPE: {
+                "kind": "org.matrix.msc3381.poll.disclosed",
+                "max_selections": 1,
+                "question": { TEXT_KEY: "Pizza?" },
+                "answers": [{ "id": "1", TEXT_KEY: "Yes" }, { "id": "2", TEXT_KEY: "No" }],
+            },
+            TEXT_KEY: "Pizza?\n1. Yes\n2. No",
+        })
+    }
+
+    #[test]
+    fn latest_response_counts() {
+        let results = tally(
+            &start(),
+            vec![
+                response("@a:x", &["1"], 2),
+                response("@a:x", &["2"], 1),
+                response("@b:x", &["1"], 1),
+                response("@c:x", &["2"], 1),
+                response("@c:x", &[], 3),
+            ],
+        );
+        assert_eq!(results.question, "Pizza?");
+        assert_eq!(results.answers[0].votes, 2);
+        assert_eq!(results.answers[1].votes, 0);
+        assert_eq!(results.votes.len(), 2);
+        assert_eq!(results.winners()[0].text, "Yes");
+        assert_eq!(
+            results.to_text(),
+            "The poll has ended: Pizza?\nYes: 2 votes\nNo: 0 votes"
+        );
+    }
+
+    #[test]
+    fn invalid_answers_are_ignored() {
+        let results = tally(
+            &start(),
+            vec![
+                response("@a:x", &["3"], 1),
+                response("@b:x", &["2", "1"], 1),
+            ],
+        );
+        assert_eq!(results.answers[0].votes, 0);
+        assert_eq!(results.answers[1].votes, 1);
+        assert_eq!(results.votes["@b:x"], vec!["2".to_string()]);
+        assert!(results.to_text().ends_with("No: 1 vote"));
+    }
+
+    #[test]
+    fn stable_format() {
+        let start = json!({
+            "m.poll": {
+                "max_selections": 2,
+                "question": { "m.text": [{ "body": "Colors?" }] },
+                "answers": [
+                    { "m.id": "r", "m.text": [{ "body": "Red" }] },
+                    { "m.id": "g", "m.text": [{ "body": "Green" }] },
+                ],
+            },
+        });
+        let results = tally(&start, vec![response("@a:x", &["r", "g"], 1)]);
+        assert_eq!(results.question, "Colors?");
+        assert_eq!(results.answers[1].text, "Green");
+        assert_eq!(results.winners().len(), 2);
+    }
+
+    #[test]
+    fn responses_in_both_formats() {
+        let stable = json!({
+            "type": "m.poll.response",
+            "event_id": "$r1",
+            "sender": "@a:x",
+            "content": {
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$poll" },
+                "m.selections": ["1"],
+            },
+        });
+        let unstable = json!({
+            "type": "org.matrix.msc3381.poll.response",
+            "event_id": "$r2",
+            "sender": "@b:x",
+            "content": {
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$poll" },
+                "org.matrix.msc3381.poll.response": { "answers": ["2"] },
+            },
+        });
+        let response = PollResponse::from_event("!r:x", &stable).unwrap();
+        assert_eq!(response.poll_id, "$poll");
+        assert_eq!(response.answers, vec!["1".to_string()]);
+        let response = PollResponse::from_event("!r:x", &unstable).unwrap();
+        assert_eq!(response.sender, "@b:x");
+        assert_eq!(response.answers, vec!["2".to_string()]);
+
+        let message = json!({ "type": "m.room.message", "content": { "body": "hi" } });
+        assert!(PollResponse::from_event("!r:x", &message).is_none());
+    }
+}
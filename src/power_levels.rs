@@ -0,0 +1,180 @@
+use crate::{ActiveBot, Error, MatrixBot};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+/// Power levels of each room, as seen in the sync or fetched by the bot
+pub(crate) type PowerLevelCache = Arc<Mutex<HashMap<String, PowerLevels>>>;
+
+/// Something a user can do in a room, that requires a certain power level
+pub enum RoomAction<'a> {
+    Invite,
+    Kick,
+    Ban,
+    /// Remove events of other users
+    Redact,
+    /// Send a message-event of the given type (e.g. "m.room.message")
+    SendMessage(&'a str),
+    /// Send a state-event of the given type (e.g. "m.room.topic")
+    SendState(&'a str),
+}
+
+/// The content of the "m.room.power_levels"-state of a room
+#[derive(Clone, Debug)]
+pub struct PowerLevels {
+    content: JsonValue,
+}
+
+impl PowerLevels {
+    /// The power level of the given user in this room
+    pub fn user_level(&self, user_id: &str) -> i64 {
+        self.content["users"][user_id]
+            .as_i64()
+            .unwrap_or_else(|| self.level("users_default", 0))
+    }
+
+    /// The power level needed to do the given action in this room
+    pub fn required_level(&self, action: &RoomAction) -> i64 {
+        match action {
+            RoomAction::Invite => self.level("invite", 0),
+            RoomAction::Kick => self.level("kick", 50),
+            RoomAction::Ban => self.level("ban", 50),
+            RoomAction::Redact => self.level("redact", 50),
+            RoomAction::SendMessage(event_type) => self.content["events"][*event_type]
+                .as_i64()
+                .unwrap_or_else(|| self.level("events_default", 0)),
+            RoomAction::SendState(event_type) => self.content["events"][*event_type]
+                .as_i64()
+                .unwrap_or_else(|| self.level("state_default", 50)),
+        }
+    }
+
+    /// Whether the given user is allowed to do the given action in this room
+    pub fn is_allowed(&self, user_id: &str, action: &RoomAction) -> bool {
+        self.user_level(user_id) >= self.required_level(action)
+    }
+
+    /// All users with an explicitly set power level
+    pub fn users(&self) -> HashMap<String, i64> {
+        self.content["users"]
+            .as_object()
+            .map(|users| {
+                users
+                    .iter()
+                    .filter_map(|(user, level)| level.as_i64().map(|l| (user.clone(), l)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn level(&self, key: &str, default: i64) -> i64 {
+        self.content[key].as_i64().unwrap_or(default)
+    }
+}
+
+/// Power levels. They are taken from the sync and only fetched for rooms the sync did not
+/// tell the bot about yet.
+impl ActiveBot {
+    /// The power levels of the given room
+    pub fn power_levels(&self, room_id: &str) -> Result<PowerLevels, Error> {
+        if let Some(levels) = self.power_level_cache.lock().unwrap().get(room_id) {
+            return Ok(levels.clone());
+        }
+
+        debug!(target: "matrix_bot_api::rooms", room = room_id, "Fetching power levels");
+        let levels = PowerLevels {
+            content: self.fetch_power_levels(room_id)?,
+        };
+        self.power_level_cache
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string(), levels.clone());
+        Ok(levels)
+    }
+
+    /// The power level of the given user in the given room
+    pub fn user_power_level(&self, room_id: &str, user_id: &str) -> Result<i64, Error> {
+        Ok(self.power_levels(room_id)?.user_level(user_id))
+    }
+
+    /// Whether the given user is allowed to do the given action in the given room
+    pub fn is_allowed(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        action: RoomAction,
+    ) -> Result<bool, Error> {
+        Ok(self.power_levels(room_id)?.is_allowed(user_id, &action))
+    }
+
+    /// Whether the bot itself is allowed to do the given action in the given room
+    pub fn bot_is_allowed(&self, room_id: &str, action: RoomAction) -> Result<bool, Error> {
+        let uid = self.user_id();
+        self.is_allowed(room_id, &uid, action)
+    }
+
+    /// Sets the power level of the given user in the given room
+    pub fn set_power_level(&self, room_id: &str, user_id: &str, level: i64) -> Result<(), Error> {
+        self.change_power_level(room_id, user_id, Some(level))
+    }
+
+    /// Removes the explicitly set power level of the given user in the given room,
+    /// so that the user falls back to the default level of the room
+    pub fn reset_power_level(&self, room_id: &str, user_id: &str) -> Result<(), Error> {
+        self.change_power_level(room_id, user_id, None)
+    }
+
+    fn change_power_level(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        level: Option<i64>,
+    ) -> Result<(), Error> {
+        // Always start from the current state, to not overwrite other changes
+        let mut content = self.fetch_power_levels(room_id)?;
+        if !content["users"].is_object() {
+            content["users"] = json!({});
+        }
+        if let Some(users) = content["users"].as_object_mut() {
+            match level {
+                Some(level) => users.insert(user_id.to_string(), json!(level)),
+                None => users.remove(user_id),
+            };
+        }
+
+        info!(
+            target: "matrix_bot_api::rooms",
+            room = room_id,
+            user = user_id,
+            "Changing power level to {:?}",
+            level
+        );
+        let path = format!("rooms/{}/state/m.room.power_levels", encode_uid(room_id));
+        self.api("put", &path, &content)?;
+        self.power_level_cache
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string(), PowerLevels { content });
+        Ok(())
+    }
+
+    fn fetch_power_levels(&self, room_id: &str) -> Result<JsonValue, Error> {
+        let path = format!("rooms/{}/state/m.room.power_levels", encode_uid(room_id));
+        self.api("get", &path, &JsonValue::Null)
+    }
+}
+
+impl MatrixBot {
+    /// Caches the content of a "m.room.power_levels"-event of the sync
+    pub(crate) fn update_power_levels(&self, room_id: &str, content: &JsonValue) {
+        self.power_level_cache.lock().unwrap().insert(
+            room_id.to_string(),
+            PowerLevels {
+                content: content.clone(),
+            },
+        );
+    }
+}
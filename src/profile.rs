@@ -0,0 +1,93 @@
+use crate::{ActiveBot, Error};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use tracing::info;
+
+/// The presence of the bot, shown by clients next to its name
+pub enum Presence {
+    Online,
+    /// Away or busy
+    Unavailable,
+    Offline,
+}
+
+/// The global profile of a user
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    /// The mxc://-url of the avatar
+    pub avatar_url: Option<String>,
+}
+
+/// Profile and presence. All these calls are blocking.
+impl ActiveBot {
+    /// The global profile of the given user
+    pub fn profile(&self, user_id: &str) -> Result<Profile, Error> {
+        let path = format!("profile/{}", encode_uid(user_id));
+        let js = self.api("get", &path, &JsonValue::Null)?;
+        Ok(Profile {
+            display_name: js["displayname"].as_str().map(String::from),
+            avatar_url: js["avatar_url"].as_str().map(String::from),
+        })
+    }
+
+    /// Sets the global display name of the bot
+    pub fn set_display_name(&self, name: &str) -> Result<(), Error> {
+        info!(target: "matrix_bot_api::profile", name = name, "Setting display name");
+        let path = format!("profile/{}/displayname", encode_uid(&self.user_id()));
+        self.api("put", &path, &json!({ "displayname": name }))
+            .map(|_| ())
+    }
+
+    /// Uploads the given image and sets it as the avatar of the bot.
+    /// Returns the mxc://-url of the image.
+    pub fn set_avatar(&self, image: Vec<u8>) -> Result<String, Error> {
+        let url = self.upload(image)?;
+        self.set_avatar_url(&url)?;
+        Ok(url)
+    }
+
+    /// Sets the avatar of the bot to an already uploaded image (see `ActiveBot::upload()`)
+    pub fn set_avatar_url(&self, url: &str) -> Result<(), Error> {
+        info!(target: "matrix_bot_api::profile", url = url, "Setting avatar");
+        let path = format!("profile/{}/avatar_url", encode_uid(&self.user_id()));
+        self.api("put", &path, &json!({ "avatar_url": url }))
+            .map(|_| ())
+    }
+
+    /// Sets the display name of the bot in the given room only
+    pub fn set_room_display_name(&self, room_id: &str, name: &str) -> Result<(), Error> {
+        info!(
+            target: "matrix_bot_api::profile",
+            room = room_id,
+            name = name,
+            "Setting display name in room"
+        );
+        let path = format!(
+            "rooms/{}/state/m.room.member/{}",
+            encode_uid(room_id),
+            encode_uid(&self.user_id())
+        );
+        // Keep the avatar and everything else of the membership
+        let mut content = self.api("get", &path, &JsonValue::Null)?;
+        content["displayname"] = json!(name);
+        self.api("put", &path, &content).map(|_| ())
+    }
+
+    /// Sets the presence of the bot, with an optional status message
+    /// (e.g. `Presence::Unavailable` with "busy: running backups")
+    pub fn set_presence(&self, presence: Presence, status_msg: Option<&str>) -> Result<(), Error> {
+        let presence = match presence {
+            Presence::Online => "online",
+            Presence::Unavailable => "unavailable",
+            Presence::Offline => "offline",
+        };
+        let mut attrs = json!({ "presence": presence });
+        if let Some(status_msg) = status_msg {
+            attrs["status_msg"] = json!(status_msg);
+        }
+        let path = format!("presence/{}/status", encode_uid(&self.user_id()));
+        self.api("put", &path, &attrs).map(|_| ())
+    }
+}
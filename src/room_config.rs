@@ -0,0 +1,435 @@
+use crate::handlers::{split_command, Message};
+use crate::{ActiveBot, Error, MatrixBot, MessageType, RoomAction};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+/// Configuration of each room the bot looked at
+pub(crate) type RoomConfigCache = Arc<Mutex<HashMap<String, RoomConfig>>>;
+
+/// The type of the room account data, the configuration is stored in
+const ACCOUNT_DATA_TYPE: &str = "matrix_bot_api.room_config";
+
+/// Per-room configuration of the bot: Which handlers are disabled, the command prefix
+/// and arbitrary settings of the handlers.
+///
+/// It is stored as account data of the bot in each room, so it survives restarts.
+/// Everything not stored falls back to the defaults of the room
+/// (see `MatrixBot::set_room_defaults()`).
+#[derive(Clone, Debug)]
+pub struct RoomConfig {
+    content: JsonValue,
+}
+
+impl RoomConfig {
+    /// Whether the handler with the given name gets messages of this room.
+    /// The name is either the full name of the handler or the last part of it
+    /// (e.g. "StatelessHandler" for "matrix_bot_api::handlers::StatelessHandler").
+    pub fn is_enabled(&self, handler_name: &str) -> bool {
+        !self.content["disabled"]
+            .as_array()
+            .map(|disabled| {
+                disabled
+                    .iter()
+                    .filter_map(|name| name.as_str())
+                    .any(|name| name_matches(handler_name, name))
+            })
+            .unwrap_or(false)
+    }
+
+    /// The command prefix of this room, if one is set
+    pub fn prefix(&self) -> Option<&str> {
+        self.content["prefix"].as_str()
+    }
+
+    /// The setting with the given key, if it is set
+    pub fn setting(&self, key: &str) -> Option<&JsonValue> {
+        self.content["settings"].get(key)
+    }
+
+    fn set_enabled(&mut self, handler_name: &str, enabled: bool) {
+        let mut disabled: Vec<JsonValue> = self.content["disabled"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| name.as_str() != Some(handler_name))
+            .collect();
+        if !enabled {
+            disabled.push(json!(handler_name));
+        }
+        self.content["disabled"] = json!(disabled);
+    }
+}
+
+/// Whether the given name of a handler is meant by the given (possibly shortened) name
+fn name_matches(handler_name: &str, name: &str) -> bool {
+    handler_name == name || handler_name.rsplit("::").next() == Some(name)
+}
+
+/// Overwrites the defaults with the stored configuration.
+/// Values that were reset (null) keep their default.
+fn merge(content: &mut JsonValue, stored: &JsonValue) {
+    for (key, value) in stored.as_object().into_iter().flatten() {
+        match (key.as_str(), value) {
+            (_, JsonValue::Null) => (),
+            ("settings", JsonValue::Object(settings)) => {
+                if !content["settings"].is_object() {
+                    content["settings"] = json!({});
+                }
+                for (name, setting) in settings.iter().filter(|(_, v)| !v.is_null()) {
+                    content["settings"][name] = setting.clone();
+                }
+            }
+            _ => content[key] = value.clone(),
+        }
+    }
+}
+
+/// Copies everything that changed from before to after into the stored configuration.
+/// Removed values are stored as null, which resets them to their default.
+fn copy_changes(stored: &mut JsonValue, before: &JsonValue, after: &JsonValue) {
+    if !stored.is_object() {
+        *stored = json!({});
+    }
+    let keys = |js: &JsonValue| -> Vec<String> {
+        js.as_object()
+            .map(|o| o.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+    let mut changed: Vec<String> = keys(before);
+    changed.extend(keys(after));
+    for key in changed.into_iter().filter(|key| before[key] != after[key]) {
+        if key == "settings" {
+            if !stored["settings"].is_object() {
+                stored["settings"] = json!({});
+            }
+            copy_changes(&mut stored["settings"], &before[&key], &after[&key]);
+        } else {
+            stored[&key] = after[&key].clone();
+        }
+    }
+}
+
+/// Per-room configuration. Reading it is cached, changing it is a blocking call.
+impl ActiveBot {
+    /// The configuration of the given room
+    pub fn room_config(&self, room_id: &str) -> Result<RoomConfig, Error> {
+        if let Some(config) = self.room_config_cache.lock().unwrap().get(room_id) {
+            return Ok(config.clone());
+        }
+
+        debug!(target: "matrix_bot_api::rooms", room = room_id, "Fetching room config");
+        let stored = self.stored_room_config(room_id)?;
+        let mut content = self.settings.lock().unwrap().room_defaults(room_id);
+        merge(&mut content, &stored);
+        let config = RoomConfig { content };
+        self.room_config_cache
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string(), config.clone());
+        Ok(config)
+    }
+
+    /// Enables or disables the handler with the given name in the given room
+    /// (see `RoomConfig::is_enabled()`)
+    pub fn set_handler_enabled(
+        &self,
+        room_id: &str,
+        handler_name: &str,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        self.change_room_config(room_id, |config| config.set_enabled(handler_name, enabled))
+    }
+
+    /// The command prefix of the given room, or None to use the prefix of each handler
+    pub fn room_prefix(&self, room_id: &str) -> Option<String> {
+        self.room_config(room_id)
+            .ok()
+            .and_then(|config| config.prefix().map(String::from))
+    }
+
+    /// Sets the command prefix of the given room. None resets it to the prefix of each handler.
+    pub fn set_room_prefix(&self, room_id: &str, prefix: Option<&str>) -> Result<(), Error> {
+        self.change_room_config(room_id, |config| {
+            config.content["prefix"] = prefix.map(|p| json!(p)).unwrap_or(JsonValue::Null)
+        })
+    }
+
+    /// The setting with the given key in the given room.
+    /// Handlers should prefix their keys with their name.
+    pub fn room_setting(&self, room_id: &str, key: &str) -> Option<JsonValue> {
+        self.room_config(room_id)
+            .ok()
+            .and_then(|config| config.setting(key).cloned())
+    }
+
+    /// Stores the given setting in the given room. Null removes the setting.
+    pub fn set_room_setting(
+        &self,
+        room_id: &str,
+        key: &str,
+        value: JsonValue,
+    ) -> Result<(), Error> {
+        self.change_room_config(room_id, |config| {
+            if !config.content["settings"].is_object() {
+                config.content["settings"] = json!({});
+            }
+            if let Some(settings) = config.content["settings"].as_object_mut() {
+                if value.is_null() {
+                    settings.remove(key);
+                } else {
+                    settings.insert(key.to_string(), value);
+                }
+            }
+        })
+    }
+
+    fn change_room_config<F>(&self, room_id: &str, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut RoomConfig),
+    {
+        let mut config = self.room_config(room_id)?;
+        let before = config.content.clone();
+        change(&mut config);
+
+        // Only store what differs from the defaults, so that they can still be changed
+        let mut stored = self.stored_room_config(room_id)?;
+        copy_changes(&mut stored, &before, &config.content);
+        info!(target: "matrix_bot_api::rooms", room = room_id, "Changing room config");
+        self.api("put", &self.room_config_path(room_id), &stored)?;
+        self.room_config_cache
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string(), config);
+        Ok(())
+    }
+
+    /// The configuration stored for the given room, without the defaults
+    fn stored_room_config(&self, room_id: &str) -> Result<JsonValue, Error> {
+        match self.api("get", &self.room_config_path(room_id), &JsonValue::Null) {
+            Ok(js) => Ok(js),
+            Err(Error::MatrixError(ref js)) if js["errcode"] == "M_NOT_FOUND" => Ok(json!({})),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn room_config_path(&self, room_id: &str) -> String {
+        let uid = self.data.lock().unwrap().user_id.clone();
+        format!(
+            "user/{}/rooms/{}/account_data/{}",
+            encode_uid(&uid),
+            encode_uid(room_id),
+            ACCOUNT_DATA_TYPE
+        )
+    }
+}
+
+impl MatrixBot {
+    /// Handles the built-in room commands (see `MatrixBot::set_room_commands()`).
+    /// Returns true, if the message was such a command.
+    pub(crate) fn handle_room_command(
+        &mut self,
+        message: &Message,
+        active_bot: &ActiveBot,
+    ) -> bool {
+        let default_prefix = match self.room_commands() {
+            Some(x) => x,
+            None => return false,
+        };
+        let prefix = active_bot
+            .room_prefix(&message.room)
+            .unwrap_or(default_prefix);
+        let (command, argument) = match split_command(&message.body, &prefix) {
+            Some(x) => x,
+            None => return false,
+        };
+        match command {
+            "features" | "enable" | "disable" | "prefix" => (),
+            #[cfg(feature = "botconfig")]
+            "reload" => (),
+            _ => return false,
+        }
+        let reply = |text: &str| {
+            active_bot.send_message(text, &message.room, MessageType::RoomNotice);
+        };
+
+        // Reloading affects all rooms, so it is for admins of the bot only
+        #[cfg(feature = "botconfig")]
+        {
+            if command == "reload" {
+                if !active_bot.is_bot_admin(&message.sender) {
+                    reply("Only admins of the bot can reload its configuration");
+                } else if self.reload_config(active_bot) {
+                    reply("Configuration reloaded");
+                } else {
+                    reply("Could not reload the configuration, see the log of the bot");
+                }
+                return true;
+            }
+        }
+
+        // Only admins of the bot and users who may change the power levels are admins of a room
+        let is_admin = if active_bot.is_bot_admin(&message.sender) {
+            Ok(true)
+        } else {
+            active_bot.is_allowed(
+                &message.room,
+                &message.sender,
+                RoomAction::SendState("m.room.power_levels"),
+            )
+        };
+        match is_admin {
+            Ok(true) => (),
+            Ok(false) => {
+                reply("Only room admins can configure the bot");
+                return true;
+            }
+            Err(e) => {
+                debug!(target: "matrix_bot_api::rooms", "Could not check power level: {:?}", e);
+                reply("Could not check your power level");
+                return true;
+            }
+        }
+
+        let result = match command {
+            "features" => {
+                let config = active_bot.room_config(&message.room);
+                let lines: Vec<String> = self
+                    .handlers
+                    .iter()
+                    .map(|handler| {
+                        let enabled = match &config {
+                            Ok(config) => config.is_enabled(handler.name()),
+                            Err(_) => true,
+                        };
+                        let state = if enabled { "enabled" } else { "disabled" };
+                        format!("{}: {}", handler.name(), state)
+                    })
+                    .collect();
+                reply(&lines.join("\n"));
+                Ok(())
+            }
+            "enable" | "disable" => {
+                let handler = self
+                    .handlers
+                    .iter()
+                    .find(|handler| name_matches(handler.name(), argument));
+                match handler {
+                    Some(handler) => {
+                        let enable = command == "enable";
+                        active_bot
+                            .set_handler_enabled(&message.room, handler.name(), enable)
+                            .map(|_| reply(&format!("{}d {}", command, handler.name())))
+                    }
+                    None => {
+                        reply(&format!("Unknown feature \"{}\"", argument));
+                        Ok(())
+                    }
+                }
+            }
+            _ => {
+                let new_prefix = if argument.is_empty() || argument == "reset" {
+                    None
+                } else {
+                    Some(argument)
+                };
+                active_bot
+                    .set_room_prefix(&message.room, new_prefix)
+                    .map(|_| match new_prefix {
+                        Some(p) => reply(&format!("Command prefix is now \"{}\"", p)),
+                        None => reply("Command prefix reset"),
+                    })
+            }
+        };
+        if let Err(e) = result {
+            reply(&format!("Could not change the configuration: {:?}", e));
+        }
+        true
+    }
+}
+
+/// Sets the configuration of a room without a homeserver, for tests of handlers
+#[cfg(test)]
+impl ActiveBot {
+    pub(crate) fn cache_room_config(&self, room_id: &str, content: JsonValue) {
+        self.room_config_cache
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string(), RoomConfig { content });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_values_overwrite_defaults() {
+        let mut content = json!({
+            "prefix": "!",
+            "disabled": ["dice"],
+            "settings": { "greeting": "Hi", "language": "en" },
+        });
+        let stored = json!({
+            "prefix": null,
+            "disabled": [],
+            "settings": { "greeting": "Moin", "language": null, "color": "red" },
+        });
+        merge(&mut content, &stored);
+        assert_eq!(
+            content,
+            json!({
+                "prefix": "!",
+                "disabled": [],
+                "settings": { "greeting": "Moin", "language": "en", "color": "red" },
+            })
+        );
+
+        let config = RoomConfig { content };
+        assert!(config.is_enabled("matrix_bot_api::handlers::Dice"));
+        assert_eq!(config.prefix(), Some("!"));
+        assert_eq!(config.setting("color"), Some(&json!("red")));
+    }
+
+    #[test]
+    fn only_changes_are_stored() {
+        let before = json!({
+            "prefix": "!",
+            "disabled": [],
+            "settings": { "greeting": "Hi", "language": "en" },
+        });
+        let after = json!({
+            "disabled": ["dice"],
+            "settings": { "greeting": "Moin", "language": "en" },
+        });
+        let mut stored = json!({ "settings": { "color": "red" } });
+        copy_changes(&mut stored, &before, &after);
+        assert_eq!(
+            stored,
+            json!({
+                "prefix": null,
+                "disabled": ["dice"],
+                "settings": { "color": "red", "greeting": "Moin" },
+            })
+        );
+
+        // Without changes, nothing is stored
+        let mut stored = JsonValue::Null;
+        copy_changes(&mut stored, &before, &before);
+        assert_eq!(stored, json!({}));
+    }
+
+    #[test]
+    fn handler_names() {
+        let mut config = RoomConfig { content: json!({}) };
+        config.set_enabled("Dice", false);
+        assert!(!config.is_enabled("bot::Dice"));
+        assert!(config.is_enabled("bot::Dice2"));
+        config.set_enabled("Dice", true);
+        assert!(config.is_enabled("bot::Dice"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{ActiveBot, Error};
+use fractal_matrix_api::util::encode_uid;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use tracing::info;
+
+/// Settings for a new room, see [`ActiveBot::create_room`].
+///
+/// # Example
+/// ```no_run
+/// # use matrix_bot_api::{ActiveBot, RoomOptions};
+/// # fn f(bot: &ActiveBot) {
+/// let options = RoomOptions::new()
+///     .name("Onboarding")
+///     .topic("Welcome to the team!")
+///     .invite("@newbie:your.homeserver");
+/// let room_id = bot.create_room(&options).unwrap();
+/// # }
+/// ```
+///
+/// [`ActiveBot::create_room`]: struct.ActiveBot.html#method.create_room
+#[derive(Clone, Default)]
+pub struct RoomOptions {
+    name: Option<String>,
+    topic: Option<String>,
+    alias: Option<String>,
+    invite: Vec<String>,
+    is_direct: bool,
+    public: bool,
+}
+
+impl RoomOptions {
+    /// A private room without name, topic or invites
+    pub fn new() -> RoomOptions {
+        RoomOptions::default()
+    }
+
+    /// The name of the room
+    pub fn name(mut self, name: &str) -> RoomOptions {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// The topic of the room
+    pub fn topic(mut self, topic: &str) -> RoomOptions {
+        self.topic = Some(topic.to_string());
+        self
+    }
+
+    /// The local part of the alias of the room (e.g. "foo" for "#foo:your.homeserver")
+    pub fn alias(mut self, alias: &str) -> RoomOptions {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    /// Invite the given user-id into the new room. Can be called multiple times.
+    pub fn invite(mut self, user_id: &str) -> RoomOptions {
+        self.invite.push(user_id.to_string());
+        self
+    }
+
+    /// Mark the room as a direct chat with the invited users
+    /// Default: false
+    pub fn direct(mut self, is_direct: bool) -> RoomOptions {
+        self.is_direct = is_direct;
+        self
+    }
+
+    /// Publish the room in the room directory and allow everyone to join
+    /// Default: false
+    pub fn public(mut self, public: bool) -> RoomOptions {
+        self.public = public;
+        self
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let (visibility, preset) = if self.public {
+            ("public", "public_chat")
+        } else if self.is_direct {
+            ("private", "trusted_private_chat")
+        } else {
+            ("private", "private_chat")
+        };
+
+        let mut attrs = json!({
+            "visibility": visibility,
+            "preset": preset,
+            "invite": self.invite,
+            "is_direct": self.is_direct,
+        });
+        if let Some(name) = &self.name {
+            attrs["name"] = json!(name);
+        }
+        if let Some(topic) = &self.topic {
+            attrs["topic"] = json!(topic);
+        }
+        if let Some(alias) = &self.alias {
+            attrs["room_alias_name"] = json!(alias);
+        }
+        attrs
+    }
+}
+
+/// Room administration. All these calls are blocking and return the error
+/// of the homeserver, if the bot is not allowed to do this.
+impl ActiveBot {
+    /// Creates a new room and returns its room-id
+    pub fn create_room(&self, options: &RoomOptions) -> Result<String, Error> {
+        let js = self.api("post", "createRoom", &options.to_json())?;
+        let room_id = room_id_of(js)?;
+        info!(target: "matrix_bot_api::rooms", room = room_id.as_str(), "Created room");
+        Ok(room_id)
+    }
+
+    /// Creates a new direct chat with the given user and returns its room-id
+    pub fn create_direct_room(&self, user_id: &str) -> Result<String, Error> {
+        self.create_room(&RoomOptions::new().invite(user_id).direct(true))
+    }
+
+    /// Joins the given room and returns its room-id.
+    ///  * room:        The room-id or an alias (e.g. "#foo:your.homeserver")
+    ///  * via_servers: Servers to join through, if the homeserver of the bot is not
+    ///    in the room yet (e.g. the server of the room-id or alias)
+    pub fn join_room(&self, room: &str, via_servers: &[&str]) -> Result<String, Error> {
+        info!(target: "matrix_bot_api::rooms", room = room, "Joining room");
+        let params: Vec<(&str, String)> = via_servers
+            .iter()
+            .map(|server| ("server_name", server.to_string()))
+            .collect();
+        let path = format!("join/{}", encode_uid(room));
+        let js = self.versioned_api("post", "r0", &path, &params, &json!({}))?;
+        room_id_of(js)
+    }
+
+    /// Returns the room-id of the given alias (e.g. "#foo:your.homeserver")
+    pub fn resolve_alias(&self, alias: &str) -> Result<String, Error> {
+        let path = format!("directory/room/{}", encode_uid(alias));
+        let js = self.api("get", &path, &JsonValue::Null)?;
+        room_id_of(js)
+    }
+
+    /// Invites the given user into the given room
+    pub fn invite(&self, room_id: &str, user_id: &str) -> Result<(), Error> {
+        self.membership_change(room_id, "invite", user_id, None)
+    }
+
+    /// Kicks the given user out of the given room, with an optional reason
+    pub fn kick(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership_change(room_id, "kick", user_id, reason)
+    }
+
+    /// Bans the given user from the given room, with an optional reason
+    pub fn ban(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership_change(room_id, "ban", user_id, reason)
+    }
+
+    /// Lifts the ban of the given user in the given room
+    pub fn unban(&self, room_id: &str, user_id: &str) -> Result<(), Error> {
+        self.membership_change(room_id, "unban", user_id, None)
+    }
+
+    /// Sets the name of the given room
+    pub fn set_room_name(&self, room_id: &str, name: &str) -> Result<(), Error> {
+        self.set_state(room_id, "m.room.name", &json!({ "name": name }))
+    }
+
+    /// Sets the topic of the given room
+    pub fn set_room_topic(&self, room_id: &str, topic: &str) -> Result<(), Error> {
+        self.set_state(room_id, "m.room.topic", &json!({ "topic": topic }))
+    }
+
+    /// Sets the avatar of the given room.
+    ///  * url: The mxc://-url of the image (see `ActiveBot::upload()`)
+    pub fn set_room_avatar(&self, room_id: &str, url: &str) -> Result<(), Error> {
+        self.set_state(room_id, "m.room.avatar", &json!({ "url": url }))
+    }
+
+    fn membership_change(
+        &self,
+        room_id: &str,
+        action: &str,
+        user_id: &str,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut attrs = json!({ "user_id": user_id });
+        if let Some(reason) = reason {
+            attrs["reason"] = json!(reason);
+        }
+        info!(
+            target: "matrix_bot_api::rooms",
+            room = room_id,
+            user = user_id,
+            action = action,
+            "Changing membership"
+        );
+        let path = format!("rooms/{}/{}", encode_uid(room_id), action);
+        self.api("post", &path, &attrs).map(|_| ())
+    }
+
+    /// Sends a state event with an empty state key to the given room
+    fn set_state(&self, room_id: &str, event_type: &str, content: &JsonValue) -> Result<(), Error> {
+        let path = format!("rooms/{}/state/{}", encode_uid(room_id), event_type);
+        self.api("put", &path, content).map(|_| ())
+    }
+}
+
+/// The room-id in the response of the homeserver
+fn room_id_of(js: JsonValue) -> Result<String, Error> {
+    match js["room_id"].as_str() {
+        Some(room_id) => Ok(room_id.to_string()),
+        None => Err(Error::MatrixError(js)),
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{ActiveBot, Error, MatrixBot};
+use fractal_matrix_api::types::Room;
+use fractal_matrix_api::util::encode_uid;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// State of each room the bot looked at
+pub(crate) type RoomStateCache = Arc<Mutex<HashMap<String, RoomState>>>;
+
+/// The state of a room, as seen by the bot.
+/// It is fetched once per room and kept up to date by the sync.
+#[derive(Clone, Debug, Default)]
+pub struct RoomState {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub canonical_alias: Option<String>,
+    /// Whether messages are end-to-end encrypted (see `ActiveBot::is_encrypted()`)
+    pub encrypted: bool,
+    /// Whether the room is a direct chat of the bot with a user
+    pub is_direct: bool,
+    /// All joined members with their display name in this room (if they have one)
+    pub members: HashMap<String, Option<String>>,
+}
+
+impl RoomState {
+    /// The name of the given user to address them with:
+    /// The display name in this room, if there is one, or the user-id otherwise
+    pub fn display_name(&self, user_id: &str) -> String {
+        match self.members.get(user_id) {
+            Some(Some(name)) => name.clone(),
+            _ => user_id.to_string(),
+        }
+    }
+
+    /// Whether the given user has joined the room
+    pub fn is_member(&self, user_id: &str) -> bool {
+        self.members.contains_key(user_id)
+    }
+
+    fn from_events(room_id: &str, events: &[JsonValue]) -> RoomState {
+        let mut state = RoomState {
+            room_id: room_id.to_string(),
+            ..RoomState::default()
+        };
+        for event in events {
+            state.apply(event);
+        }
+        state
+    }
+
+    /// Applies a state event to this state
+    fn apply(&mut self, event: &JsonValue) {
+        let content = &event["content"];
+        match event["type"].as_str().unwrap_or_default() {
+            "m.room.name" => self.name = non_empty(&content["name"]),
+            "m.room.topic" => self.topic = non_empty(&content["topic"]),
+            "m.room.canonical_alias" => self.canonical_alias = non_empty(&content["alias"]),
+            "m.room.encryption" => self.encrypted = content["algorithm"].is_string(),
+            "m.room.member" => {
+                let user_id = event["state_key"].as_str().unwrap_or_default();
+                self.update_member(user_id, content);
+            }
+            _ => (),
+        }
+    }
+
+    fn update_member(&mut self, user_id: &str, content: &JsonValue) {
+        if content["membership"] == "join" {
+            let name = content["displayname"].as_str().map(String::from);
+            self.members.insert(user_id.to_string(), name);
+        } else {
+            self.members.remove(user_id);
+        }
+    }
+}
+
+/// Room state. Reading it is cached.
+impl ActiveBot {
+    /// The state of the given room: Name, topic, members, ...
+    pub fn room_state(&self, room_id: &str) -> Result<RoomState, Error> {
+        if let Some(state) = self.room_state_cache.lock().unwrap().get(room_id) {
+            return Ok(state.clone());
+        }
+
+        debug!(target: "matrix_bot_api::rooms", room = room_id, "Fetching room state");
+        let path = format!("rooms/{}/state", encode_uid(room_id));
+        let events = self.api("get", &path, &JsonValue::Null)?;
+        let events = events.as_array().cloned().unwrap_or_default();
+        let mut state = RoomState::from_events(room_id, &events);
+
+        // Rooms are direct chats, if they are in the m.direct account data, or if the bot
+        // was invited to a direct chat
+        let uid = self.user_id();
+        let invited_direct = events.iter().any(|event| {
+            event["type"] == "m.room.member"
+                && event["state_key"] == uid.as_str()
+                && event["content"]["is_direct"] == true
+        });
+        // Without the account data, the room counts as not direct (and is not cached,
+        // to ask again next time)
+        let direct_rooms = match self.direct_rooms() {
+            Ok(direct_rooms) => Some(direct_rooms),
+            Err(e) => {
+                debug!(
+                    target: "matrix_bot_api::rooms",
+                    room = room_id,
+                    "Could not fetch the direct chats: {:?}",
+                    e
+                );
+                None
+            }
+        };
+        state.is_direct = invited_direct
+            || direct_rooms
+                .iter()
+                .flat_map(HashMap::values)
+                .any(|rooms| rooms.iter().any(|room| room == room_id));
+
+        if direct_rooms.is_some() {
+            self.room_state_cache
+                .lock()
+                .unwrap()
+                .insert(room_id.to_string(), state.clone());
+        }
+        Ok(state)
+    }
+
+    /// The room name, or the canonical alias, or the room-id of the given room
+    pub fn room_display_name(&self, room_id: &str) -> String {
+        match self.room_state(room_id) {
+            Ok(state) => state
+                .name
+                .or(state.canonical_alias)
+                .unwrap_or_else(|| room_id.to_string()),
+            Err(_) => room_id.to_string(),
+        }
+    }
+
+    /// Whether the given room is a direct chat of the bot with a user
+    pub fn is_direct(&self, room_id: &str) -> bool {
+        self.room_state(room_id)
+            .map(|state| state.is_direct)
+            .unwrap_or(false)
+    }
+
+    /// The direct chats of the bot (user-id to room-ids), from the m.direct account data
+    pub(crate) fn direct_rooms(&self) -> Result<HashMap<String, Vec<String>>, Error> {
+        let path = format!("user/{}/account_data/m.direct", encode_uid(&self.user_id()));
+        let js = match self.api("get", &path, &JsonValue::Null) {
+            Ok(js) => js,
+            Err(Error::MatrixError(ref js)) if js["errcode"] == "M_NOT_FOUND" => {
+                return Ok(HashMap::new())
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(js
+            .as_object()
+            .map(|users| {
+                users
+                    .iter()
+                    .map(|(user, rooms)| {
+                        let rooms = rooms
+                            .as_array()
+                            .map(|r| {
+                                r.iter()
+                                    .filter_map(|x| x.as_str().map(String::from))
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        (user.clone(), rooms)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+impl MatrixBot {
+    /// Forgets the state of rooms the bot left
+    pub(crate) fn update_room_state(&self, rooms: &[Room]) {
+        let mut cache = self.room_state_cache.lock().unwrap();
+        for room in rooms.iter().filter(|room| room.membership.is_left()) {
+            cache.remove(&room.id);
+        }
+    }
+
+    /// Applies a state event of the sync to the cached state of its room
+    pub(crate) fn update_room_state_event(&self, room_id: &str, event: &JsonValue) {
+        if let Some(state) = self.room_state_cache.lock().unwrap().get_mut(room_id) {
+            state.apply(event);
+        }
+    }
+}
+
+fn non_empty(value: &JsonValue) -> Option<String> {
+    value.as_str().filter(|s| !s.is_empty()).map(String::from)
+}
@@ -0,0 +1,189 @@
+use crate::{ActiveBot, MatrixBot, Room};
+use fractal_matrix_api::types::RoomMembership;
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Settings of the bot, that handlers can read through the ActiveBot
+pub(crate) type SharedSettings = Arc<Mutex<Settings>>;
+
+/// Which invites the bot accepts (see `MatrixBot::set_invite_policy()`).
+/// Invites to persistent rooms are always accepted.
+#[derive(Clone, Debug)]
+pub enum InvitePolicy {
+    /// Join every room the bot is invited to
+    All,
+    /// Ignore all invites
+    Nobody,
+    /// Only accept invites of users of the given homeservers (the server part of the
+    /// user-id, e.g. "example.org")
+    Servers(Vec<String>),
+}
+
+impl InvitePolicy {
+    /// Whether an invite of the given user is accepted
+    pub fn accepts(&self, inviter: &str) -> bool {
+        match self {
+            InvitePolicy::All => true,
+            InvitePolicy::Nobody => false,
+            InvitePolicy::Servers(servers) => match inviter.find(':') {
+                Some(colon) => servers.iter().any(|s| *s == inviter[colon + 1..]),
+                None => false,
+            },
+        }
+    }
+}
+
+/// How many messages each user may send to the handlers in a given time
+/// (see `MatrixBot::set_rate_limit()`)
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub messages: usize,
+    pub per: Duration,
+}
+
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub(crate) admins: HashSet<String>,
+    pub(crate) invite_policy: InvitePolicy,
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) data_dir: Option<PathBuf>,
+    pub(crate) default_prefix: Option<String>,
+    pub(crate) room_defaults: HashMap<String, JsonValue>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            admins: HashSet::new(),
+            invite_policy: InvitePolicy::All,
+            rate_limit: None,
+            data_dir: None,
+            default_prefix: None,
+            room_defaults: HashMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// The room configuration of the given room, as long as nothing is stored for it
+    pub(crate) fn room_defaults(&self, room_id: &str) -> JsonValue {
+        let mut defaults = self
+            .room_defaults
+            .get(room_id)
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        if defaults["prefix"].is_null() {
+            if let Some(prefix) = &self.default_prefix {
+                defaults["prefix"] = json!(prefix);
+            }
+        }
+        defaults
+    }
+}
+
+/// Settings of the bot
+impl ActiveBot {
+    /// Whether the given user is an admin of the bot (see `MatrixBot::add_admin()`)
+    pub fn is_bot_admin(&self, user_id: &str) -> bool {
+        self.settings.lock().unwrap().admins.contains(user_id)
+    }
+
+    /// The directory handlers can store their data in (see `MatrixBot::set_data_dir()`)
+    pub fn data_dir(&self) -> Option<PathBuf> {
+        self.settings.lock().unwrap().data_dir.clone()
+    }
+}
+
+impl MatrixBot {
+    /// Makes the settings visible to the handlers: Those made in code, with the values of
+    /// the configuration file applied over them
+    pub(crate) fn update_settings(&mut self) {
+        #[cfg_attr(not(feature = "botconfig"), allow(unused_mut))]
+        let mut settings = self.code_settings.clone();
+        #[cfg(feature = "botconfig")]
+        {
+            if let Some(config) = &self.file_config {
+                config.apply_settings(&mut settings);
+            }
+        }
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// The prefix of the room commands (see `MatrixBot::set_room_commands()`),
+    /// the one of the configuration file first
+    pub(crate) fn room_commands(&self) -> Option<String> {
+        #[cfg(feature = "botconfig")]
+        {
+            let prefix = self
+                .file_config
+                .as_ref()
+                .and_then(|c| c.room_commands.clone());
+            if prefix.is_some() {
+                return prefix;
+            }
+        }
+        self.room_command_prefix.clone()
+    }
+
+    /// Whether the bot should join the given room it was invited to
+    pub(crate) fn accepts_invite(&self, room: &Room) -> bool {
+        let persistent = self
+            .persistent_rooms
+            .iter()
+            .any(|p| p.room == room.id || p.room_id.as_ref() == Some(&room.id));
+        let inviter = match &room.membership {
+            RoomMembership::Invited(member) => member.uid.as_str(),
+            _ => "",
+        };
+        persistent || self.settings.lock().unwrap().invite_policy.accepts(inviter)
+    }
+
+    /// Counts the message of the given user and returns true, if the user sent too many.
+    /// Admins of the bot are never limited.
+    pub(crate) fn is_rate_limited(&mut self, user_id: &str) -> bool {
+        let limit = {
+            let settings = self.settings.lock().unwrap();
+            match settings.rate_limit {
+                Some(limit) if !settings.admins.contains(user_id) => limit,
+                _ => return false,
+            }
+        };
+
+        let now = Instant::now();
+        let sent = self.rate_limits.entry(user_id.to_string()).or_default();
+        while let Some(t) = sent.front() {
+            if now.duration_since(*t) <= limit.per {
+                break;
+            }
+            sent.pop_front();
+        }
+        if sent.len() >= limit.messages {
+            debug!(target: "matrix_bot_api::dispatch", user = user_id, "User is rate limited");
+            return true;
+        }
+        sent.push_back(now);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invite_policy() {
+        assert!(InvitePolicy::All.accepts("@alice:example.org"));
+        assert!(!InvitePolicy::Nobody.accepts("@alice:example.org"));
+
+        let policy = InvitePolicy::Servers(vec!["example.org".to_string()]);
+        assert!(policy.accepts("@alice:example.org"));
+        assert!(!policy.accepts("@mallory:evil.org"));
+        assert!(!policy.accepts("@mallory:evil.org:example.org"));
+        assert!(!policy.accepts(""));
+    }
+}
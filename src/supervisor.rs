@@ -0,0 +1,216 @@
+use crate::{ActiveBot, MatrixBot};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Runs several bots with their own credentials and handlers in one process.
+///
+/// Each bot is created by a factory function, so that it can be created again,
+/// when it failed (e.g. because its handler panicked or its login failed).
+/// As soon as one bot shuts down regularly (e.g. by `ActiveBot::shutdown()`),
+/// all other bots are shut down as well and `run()` returns.
+///
+/// Every bot runs in a thread of its own (besides the threads of its backend), as
+/// `MatrixBot::run()` blocks until the bot shuts down. A slow handler therefore only
+/// delays the bot it belongs to.
+///
+/// # Example
+/// ```no_run
+/// use matrix_bot_api::{MatrixBot, Supervisor};
+/// use matrix_bot_api::handlers::StatelessHandler;
+///
+/// let mut supervisor = Supervisor::new();
+/// supervisor.add_bot("dicebot", "secret", "https://your.homeserver", || {
+///     MatrixBot::new(StatelessHandler::new())
+/// });
+/// supervisor.add_bot("echobot", "secret", "https://your.homeserver", || {
+///     MatrixBot::new(StatelessHandler::new())
+/// });
+/// supervisor.set_handle_signals(true);
+/// supervisor.run();
+/// ```
+pub struct Supervisor {
+    bots: Vec<Arc<SupervisedBot>>,
+    handle_signals: bool,
+    restart_delay: Duration,
+    state: Arc<Mutex<SupervisorState>>,
+}
+
+struct SupervisedBot {
+    user: String,
+    password: String,
+    homeserver_url: String,
+    factory: Box<dyn Fn() -> MatrixBot + Send + Sync>,
+}
+
+#[derive(Default)]
+struct SupervisorState {
+    stopping: bool,
+    /// Handles of all currently running bots, by their index
+    running: HashMap<usize, ActiveBot>,
+}
+
+impl SupervisorState {
+    fn shutdown_all(&mut self) {
+        self.stopping = true;
+        for bot in self.running.values() {
+            bot.shutdown();
+        }
+    }
+}
+
+impl Supervisor {
+    /// A supervisor without bots (see `add_bot()`)
+    pub fn new() -> Supervisor {
+        Supervisor {
+            bots: vec![],
+            handle_signals: false,
+            restart_delay: Duration::from_secs(10),
+            state: Arc::new(Mutex::new(SupervisorState::default())),
+        }
+    }
+
+    /// Add a bot, that logs in with the given credentials.
+    /// The factory is called each time the bot is (re)started and has to return
+    /// a new MatrixBot with all its handlers and settings.
+    /// Do not enable `MatrixBot::set_handle_signals()` there, use
+    /// `Supervisor::set_handle_signals()` instead.
+    pub fn add_bot<F>(&mut self, user: &str, password: &str, homeserver_url: &str, factory: F)
+    where
+        F: Fn() -> MatrixBot + Send + Sync + 'static,
+    {
+        self.bots.push(Arc::new(SupervisedBot {
+            user: user.to_string(),
+            password: password.to_string(),
+            homeserver_url: homeserver_url.to_string(),
+            factory: Box::new(factory),
+        }));
+    }
+
+    /// If true, all bots shut down gracefully on SIGINT and SIGTERM (Ctrl-C on Windows).
+    /// Default: false
+    pub fn set_handle_signals(&mut self, handle_signals: bool) {
+        self.handle_signals = handle_signals;
+    }
+
+    /// How long to wait before a failed bot is started again
+    /// Default: 10 seconds
+    pub fn set_restart_delay(&mut self, delay: Duration) {
+        self.restart_delay = delay;
+    }
+
+    /// Blocking call that runs all bots, each in its own thread.
+    /// A bot that panicked or could not log in is restarted (see `set_restart_delay()`).
+    /// Will return after all bots have been shut down.
+    pub fn run(self) {
+        if self.handle_signals {
+            let state = self.state.clone();
+            let result = ctrlc::set_handler(move || state.lock().unwrap().shutdown_all());
+            if let Err(e) = result {
+                warn!(
+                    target: "matrix_bot_api::supervisor",
+                    "Could not install signal handler: {}", e
+                );
+            }
+        }
+
+        let (tx, rx) = channel();
+        for index in 0..self.bots.len() {
+            self.start(index, Duration::from_secs(0), tx.clone());
+        }
+
+        let mut running = self.bots.len();
+        while running > 0 {
+            let (index, failed) = rx.recv().unwrap();
+            let user = self.bots[index].user.as_str();
+            let stopping = {
+                let mut state = self.state.lock().unwrap();
+                state.running.remove(&index);
+                state.stopping
+            };
+
+            if stopping {
+                running -= 1;
+            } else if failed {
+                warn!(
+                    target: "matrix_bot_api::supervisor",
+                    user = user,
+                    "Bot failed, restarting in {:?}",
+                    self.restart_delay
+                );
+                self.start(index, self.restart_delay, tx.clone());
+            } else {
+                info!(
+                    target: "matrix_bot_api::supervisor",
+                    user = user,
+                    "Bot shut down, shutting down all bots"
+                );
+                running -= 1;
+                self.state.lock().unwrap().shutdown_all();
+            }
+        }
+        info!(target: "matrix_bot_api::supervisor", "All bots shut down");
+    }
+
+    /// Starts the bot with the given index in a new thread after the given delay.
+    /// When the bot stops, its index is sent to `done`, together with whether it failed.
+    fn start(&self, index: usize, delay: Duration, done: Sender<(usize, bool)>) {
+        let bot = self.bots[index].clone();
+        let state = self.state.clone();
+        let thread_done = done.clone();
+
+        let spawned = thread::Builder::new()
+            .name(format!("matrix-bot-{}", bot.user))
+            .spawn(move || {
+                thread::sleep(delay);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let matrix_bot = (bot.factory)();
+                    {
+                        let mut state = state.lock().unwrap();
+                        if state.stopping {
+                            return Ok(());
+                        }
+                        state
+                            .running
+                            .insert(index, matrix_bot.get_activebot_clone());
+                    }
+                    info!(
+                        target: "matrix_bot_api::supervisor",
+                        user = bot.user.as_str(),
+                        "Starting bot"
+                    );
+                    matrix_bot.try_run(&bot.user, &bot.password, &bot.homeserver_url)
+                }));
+                let failed = match result {
+                    Ok(Ok(())) => false,
+                    Ok(Err(e)) => {
+                        error!(
+                            target: "matrix_bot_api::supervisor",
+                            user = bot.user.as_str(),
+                            "Bot stopped: {:?}",
+                            e
+                        );
+                        true
+                    }
+                    Err(_) => true,
+                };
+                let _ = thread_done.send((index, failed));
+            });
+
+        if let Err(e) = spawned {
+            error!(target: "matrix_bot_api::supervisor", "Could not start thread: {}", e);
+            self.state.lock().unwrap().shutdown_all();
+            let _ = done.send((index, false));
+        }
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Supervisor {
+        Supervisor::new()
+    }
+}
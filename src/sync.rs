@@ -0,0 +1,172 @@
+use crate::{ActiveBot, MatrixBot, PollResponse};
+use fractal_matrix_api::backend::BKResponse;
+use fractal_matrix_api::types::{Member, Message, Reason, Room, RoomMembership, RoomTag};
+use serde_json::json;
+use serde_json::value::Value as JsonValue;
+use std::collections::VecDeque;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::trace;
+
+/// How long the homeserver holds back a sync-request, when there is nothing new
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the bot waits after a failed sync, to not hammer the homeserver
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Events per room and sync. Rooms with more new events get a gap in their timeline.
+const TIMELINE_LIMIT: u32 = 50;
+
+/// Sync-responses the bot did not handle yet, and whether they were the first one
+pub(crate) type SyncQueue = Arc<Mutex<VecDeque<(bool, JsonValue)>>>;
+
+/// Starts the next sync in a background-thread, like the backend of fractal would.
+/// The response is put into the queue and announced by `BKResponse::Sync`.
+///
+/// The backend of fractal drops all events but messages, names, topics and members,
+/// and hides who sent an invite. That is why the bot syncs on its own.
+pub(crate) fn start(bot: &ActiveBot, queue: &SyncQueue, tx: &Sender<BKResponse>) {
+    let bot = bot.clone();
+    let queue = queue.clone();
+    let tx = tx.clone();
+    thread::spawn(move || {
+        let since = bot.data.lock().unwrap().since.clone();
+        let initial = since.is_none();
+        let mut params = vec![
+            ("timeout", TIMEOUT.as_millis().to_string()),
+            ("filter", filter(initial).to_string()),
+        ];
+        if let Some(since) = since {
+            params.push(("since", since));
+        }
+
+        match bot.versioned_api("get", "r0", "sync", &params, &JsonValue::Null) {
+            Ok(response) => {
+                let next_batch = response["next_batch"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                bot.data.lock().unwrap().since = Some(next_batch.clone()).filter(|s| !s.is_empty());
+                queue.lock().unwrap().push_back((initial, response));
+                let _ = tx.send(BKResponse::Sync(next_batch));
+            }
+            Err(e) => {
+                thread::sleep(RETRY_DELAY);
+                let _ = tx.send(BKResponse::SyncError(e));
+            }
+        }
+    });
+}
+
+/// Messages before the login are ignored, so the first sync only needs the state of the
+/// rooms. Members are only loaded, when they sent something.
+fn filter(initial: bool) -> JsonValue {
+    let limit = if initial { 1 } else { TIMELINE_LIMIT };
+    json!({
+        "room": {
+            "state": { "lazy_load_members": true },
+            "timeline": { "limit": limit },
+        },
+        "presence": { "types": [] },
+    })
+}
+
+impl MatrixBot {
+    /// Handles all sync-responses in the queue
+    pub(crate) fn handle_syncs(&mut self, active_bot: &ActiveBot) {
+        loop {
+            let next = self.sync_queue.lock().unwrap().pop_front();
+            match next {
+                Some((initial, response)) => self.handle_sync(initial, &response, active_bot),
+                None => break,
+            }
+        }
+    }
+
+    /// Updates the cached state of all rooms, answers invites and gives new messages
+    /// to the handlers (except for the first sync, as it only contains old messages)
+    fn handle_sync(&mut self, initial: bool, response: &JsonValue, active_bot: &ActiveBot) {
+        trace!(target: "matrix_bot_api::sync", "<=== sync: {}", response);
+
+        let uid = self.uid.clone().unwrap_or_default();
+        let rooms = rooms(response, &uid);
+        self.update_room_state(&rooms);
+
+        for (room_id, room) in rooms_of(response, "join") {
+            for event in state_events(room) {
+                self.update_room_state_event(room_id, event);
+                if event["state_key"] != "" {
+                    continue;
+                }
+                match event["type"].as_str().unwrap_or_default() {
+                    "m.room.power_levels" => self.update_power_levels(room_id, &event["content"]),
+                    "m.room.encryption" => self.room_encrypted(room_id),
+                    _ => (),
+                }
+            }
+        }
+
+        self.handle_rooms(rooms, active_bot);
+
+        if !initial {
+            let mut messages = vec![];
+            let mut poll_responses = vec![];
+            for (room_id, room) in rooms_of(response, "join") {
+                let timeline = &room["timeline"];
+                messages.extend(Message::from_json_events_iter(room_id, events(timeline)));
+                poll_responses.extend(
+                    events(timeline).filter_map(|event| PollResponse::from_event(room_id, event)),
+                );
+            }
+            self.handle_messages(messages, active_bot);
+            self.handle_poll_responses(poll_responses, active_bot);
+        }
+    }
+}
+
+/// The rooms of the sync-response, with the membership of the bot.
+/// Invites contain the user that sent them.
+fn rooms(response: &JsonValue, uid: &str) -> Vec<Room> {
+    let joined = rooms_of(response, "join")
+        .map(|(room_id, _)| Room::new(room_id.clone(), RoomMembership::Joined(RoomTag::None)));
+    let invited = rooms_of(response, "invite").map(|(room_id, room)| {
+        let inviter = events(&room["invite_state"])
+            .find(|event| {
+                event["type"] == "m.room.member"
+                    && event["state_key"] == uid
+                    && event["content"]["membership"] == "invite"
+            })
+            .and_then(|event| event["sender"].as_str())
+            .unwrap_or_default();
+        let inviter = Member {
+            uid: inviter.to_string(),
+            alias: None,
+            avatar: None,
+        };
+        Room::new(room_id.clone(), RoomMembership::Invited(inviter))
+    });
+    let left = rooms_of(response, "leave")
+        .map(|(room_id, _)| Room::new(room_id.clone(), RoomMembership::Left(Reason::None)));
+    joined.chain(invited).chain(left).collect()
+}
+
+/// The rooms of the given section ("join", "invite" or "leave") of the sync-response
+fn rooms_of<'a>(
+    response: &'a JsonValue,
+    section: &str,
+) -> impl Iterator<Item = (&'a String, &'a JsonValue)> {
+    response["rooms"][section].as_object().into_iter().flatten()
+}
+
+/// The events of the given part of a room ("state", "timeline", ...)
+fn events(part: &JsonValue) -> impl Iterator<Item = &JsonValue> {
+    part["events"].as_array().into_iter().flatten()
+}
+
+/// The state events of a joined room, in the order they happened: The state before the
+/// timeline and the state changes within the timeline
+fn state_events(room: &JsonValue) -> impl Iterator<Item = &JsonValue> {
+    events(&room["state"]).chain(events(&room["timeline"]).filter(|e| e["state_key"].is_string()))
+}
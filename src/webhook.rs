@@ -0,0 +1,329 @@
+//! Embedded HTTP server that relays incoming webhooks (CI, monitoring, git-hosting, ...)
+//! into rooms.
+//!
+//! Each [`WebhookRoute`] maps a path to a room. The JSON payload of a POST-request
+//! to that path is given to a template or a callback, that produces the message.
+//!
+//! # Example
+//! ```no_run
+//! use matrix_bot_api::webhook::{WebhookAuth, WebhookRoute};
+//! use matrix_bot_api::{MatrixBot, MessageType};
+//! # use matrix_bot_api::handlers::StatelessHandler;
+//!
+//! let mut bot = MatrixBot::new(StatelessHandler::new());
+//! bot.set_webhook_listener("0.0.0.0:8080");
+//!
+//! // Templates: Placeholders are paths into the JSON payload
+//! let ci = WebhookRoute::with_template(
+//!     "/ci",
+//!     "!someroomid:your.homeserver",
+//!     "Build {{build.id}} finished: {{build.status}}",
+//!     "Build <b>{{build.id}}</b> finished: {{build.status}}",
+//! )
+//! .auth(WebhookAuth::SharedSecret {
+//!     header: "X-Gitlab-Token".to_string(),
+//!     secret: "secret".to_string(),
+//! });
+//! bot.add_webhook_route(ci);
+//!
+//! // Callbacks: Do whatever you like with the payload
+//! let alerts = WebhookRoute::with_callback(
+//!     "/alerts",
+//!     "!otherroomid:your.homeserver",
+//!     |bot, room, payload| {
+//!         let text = payload["message"].as_str().unwrap_or("Unknown alert");
+//!         let html = format!("<b>{}</b>", text);
+//!         bot.send_html_message(text, &html, room, MessageType::RoomNotice);
+//!     },
+//! )
+//! .auth(WebhookAuth::HmacSha256 {
+//!     header: "X-Hub-Signature-256".to_string(),
+//!     secret: "secret".to_string(),
+//! });
+//! bot.add_webhook_route(alerts);
+//! ```
+//!
+//! [`WebhookRoute`]: struct.WebhookRoute.html
+use crate::html::Template;
+use crate::{ActiveBot, MessageType};
+use hmac::{Hmac, Mac};
+use serde_json::value::Value as JsonValue;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Read;
+use std::thread;
+use tiny_http::{Method, Request, Response, Server};
+use tracing::{debug, error, info, warn};
+
+/// Larger requests are rejected with "413 Payload Too Large"
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// How requests to a route are verified
+pub enum WebhookAuth {
+    /// Accept every request
+    None,
+    /// The given header has to contain the shared secret (e.g. GitLabs "X-Gitlab-Token")
+    SharedSecret { header: String, secret: String },
+    /// The given header has to contain the hex-encoded HMAC-SHA256 of the request body,
+    /// prefixed by "sha256=" (e.g. GitHubs "X-Hub-Signature-256")
+    HmacSha256 { header: String, secret: String },
+}
+
+enum Formatter {
+    Template(Template),
+    Callback(fn(bot: &ActiveBot, room: &str, payload: &JsonValue)),
+}
+
+/// Maps a path of the webhook-listener to a room
+pub struct WebhookRoute {
+    path: String,
+    room: String,
+    auth: WebhookAuth,
+    notice: bool,
+    formatter: Formatter,
+}
+
+impl WebhookRoute {
+    /// Relays payloads sent to `path` to the given room, using the given templates.
+    /// Placeholders look like `{{some.path.0.value}}` and will be replaced by the value at this
+    /// path in the JSON payload (here `payload["some"]["path"][0]["value"]`).
+    /// In the html-template, all values will be HTML-escaped (see `html::Template`).
+    /// Unknown paths are replaced by an empty string.
+    pub fn with_template(path: &str, room: &str, plain: &str, html: &str) -> WebhookRoute {
+        WebhookRoute::new(path, room, Formatter::Template(Template::new(plain, html)))
+    }
+
+    /// Calls the given function for each payload sent to `path`.
+    /// Callback-function:
+    /// * bot:     This bot
+    /// * room:    The room-id of this route
+    /// * payload: The JSON payload of the request
+    pub fn with_callback(
+        path: &str,
+        room: &str,
+        callback: fn(bot: &ActiveBot, room: &str, payload: &JsonValue),
+    ) -> WebhookRoute {
+        WebhookRoute::new(path, room, Formatter::Callback(callback))
+    }
+
+    /// How requests to this route are verified.
+    /// Default: WebhookAuth::None
+    pub fn auth(mut self, auth: WebhookAuth) -> WebhookRoute {
+        self.auth = auth;
+        self
+    }
+
+    /// Send templated messages as room notices instead of text messages.
+    /// Has no effect on callbacks.
+    /// Default: false
+    pub fn as_notice(mut self, notice: bool) -> WebhookRoute {
+        self.notice = notice;
+        self
+    }
+
+    fn new(path: &str, room: &str, formatter: Formatter) -> WebhookRoute {
+        WebhookRoute {
+            path: path.to_string(),
+            room: room.to_string(),
+            auth: WebhookAuth::None,
+            notice: false,
+            formatter,
+        }
+    }
+
+    fn verify(&self, request: &Request, body: &[u8]) -> bool {
+        match &self.auth {
+            WebhookAuth::None => true,
+            WebhookAuth::SharedSecret { header, secret } => match header_value(request, header) {
+                Some(value) => constant_time_eq(value.as_bytes(), secret.as_bytes()),
+                None => false,
+            },
+            WebhookAuth::HmacSha256 { header, secret } => match header_value(request, header) {
+                Some(value) => verify_hmac(secret, &value, body),
+                None => false,
+            },
+        }
+    }
+
+    fn relay(&self, bot: &ActiveBot, payload: &JsonValue) {
+        match &self.formatter {
+            Formatter::Template(template) => {
+                let (plain, html) = template.render(payload);
+                let msgtype = if self.notice {
+                    MessageType::RoomNotice
+                } else {
+                    MessageType::TextMessage
+                };
+                bot.send_html_message(&plain, &html, &self.room, msgtype);
+            }
+            Formatter::Callback(callback) => callback(bot, &self.room, payload),
+        }
+    }
+}
+
+/// Starts a background-thread that serves the given routes on the given address
+pub(crate) fn serve(addr: &str, routes: Vec<WebhookRoute>, bot: ActiveBot) {
+    let server = match Server::http(addr) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                target: "matrix_bot_api::webhook",
+                "Could not start webhook-listener on {}: {}", addr, e
+            );
+            return;
+        }
+    };
+    info!(target: "matrix_bot_api::webhook", addr = addr, "Listening for webhooks");
+
+    let routes: HashMap<String, WebhookRoute> = routes
+        .into_iter()
+        .map(|route| (route.path.clone(), route))
+        .collect();
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let status = handle_request(&routes, &bot, &mut request);
+            if let Err(e) = request.respond(Response::empty(status)) {
+                error!(target: "matrix_bot_api::webhook", "Could not answer request: {}", e);
+            }
+        }
+    });
+}
+
+fn handle_request(
+    routes: &HashMap<String, WebhookRoute>,
+    bot: &ActiveBot,
+    request: &mut Request,
+) -> u16 {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let route = match routes.get(&path) {
+        Some(x) => x,
+        None => {
+            debug!(target: "matrix_bot_api::webhook", path = path.as_str(), "Unknown route");
+            return 404;
+        }
+    };
+
+    if *request.method() != Method::Post {
+        return 405;
+    }
+
+    let mut body = vec![];
+    let mut reader = request.as_reader().take(MAX_BODY + 1);
+    if let Err(e) = reader.read_to_end(&mut body) {
+        warn!(target: "matrix_bot_api::webhook", "Could not read request body: {}", e);
+        return 400;
+    }
+    if body.len() as u64 > MAX_BODY {
+        warn!(
+            target: "matrix_bot_api::webhook",
+            path = path.as_str(),
+            "Rejected request larger than {} bytes",
+            MAX_BODY
+        );
+        return 413;
+    }
+
+    if !route.verify(request, &body) {
+        warn!(
+            target: "matrix_bot_api::webhook",
+            path = path.as_str(),
+            "Rejected request with invalid secret or signature"
+        );
+        return 401;
+    }
+
+    let payload: JsonValue = match serde_json::from_slice(&body) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(target: "matrix_bot_api::webhook", "Payload is not valid JSON: {}", e);
+            return 400;
+        }
+    };
+
+    debug!(
+        target: "matrix_bot_api::webhook",
+        path = path.as_str(),
+        room = route.room.as_str(),
+        "Relaying webhook"
+    );
+    route.relay(bot, &payload);
+    204
+}
+
+fn header_value(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+/// Whether the header value is "sha256=" followed by the hex-encoded HMAC-SHA256 of the body
+fn verify_hmac(secret: &str, value: &str, body: &[u8]) -> bool {
+    let signature = match value.strip_prefix("sha256=").map(hex::decode) {
+        Some(Ok(x)) => x,
+        _ => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    mac.input(body);
+    mac.verify(&signature).is_ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const SIGNATURE: &str = "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+
+    #[test]
+    fn hmac_valid() {
+        assert!(verify_hmac("key", &format!("sha256={}", SIGNATURE), BODY));
+        let upper = SIGNATURE.to_uppercase();
+        assert!(verify_hmac("key", &format!("sha256={}", upper), BODY));
+    }
+
+    #[test]
+    fn hmac_invalid() {
+        let header = format!("sha256={}", SIGNATURE);
+        assert!(!verify_hmac("other key", &header, BODY));
+        assert!(!verify_hmac(
+            "key",
+            &header,
+            b"The quick brown fox jumps over the lazy cat"
+        ));
+        assert!(!verify_hmac("key", &header[..header.len() - 2], BODY));
+        assert!(!verify_hmac("key", "sha256=not hex", BODY));
+        assert!(!verify_hmac("key", "sha256=", BODY));
+    }
+
+    #[test]
+    fn hmac_needs_prefix() {
+        assert!(!verify_hmac("key", SIGNATURE, BODY));
+        assert!(!verify_hmac("key", &format!("sha1={}", SIGNATURE), BODY));
+        assert!(!verify_hmac(
+            "key",
+            &format!("sha256=sha256={}", SIGNATURE),
+            BODY
+        ));
+    }
+
+    #[test]
+    fn shared_secret() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}